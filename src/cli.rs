@@ -0,0 +1,351 @@
+// containerspy command line parsing
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Result};
+use confique::meta::{Field, FieldKind};
+use confique::serde::de::{self, value::Error as DeError, IntoDeserializer, Visitor};
+use confique::serde::forward_to_deserialize_any;
+use confique::Config;
+
+use crate::built_info;
+use crate::config::{ConfigError, CspyConfig};
+
+pub enum Action {
+	Run,
+	Help,
+	Version,
+	PrintConfig,
+	CheckConfig,
+	Healthcheck,
+}
+
+pub struct Cli {
+	pub action: Action,
+	pub config_path: Option<String>,
+	/// config values passed as flags, keyed by config field name, not yet deserialized
+	pub flags: BTreeMap<&'static str, String>,
+}
+
+/// flags that are not config options, with their argument placeholder (if any) and a description
+static CLI_FLAGS: &[(&str, &str, &str)] = &[
+	("--config", "<path>", "Config file to load (overrides CSPY_CONFIG)"),
+	("--print-config", "", "Print the effective config and where each value came from, then exit"),
+	("--check-config", "", "Validate the config and exit, with a non-zero exit code if it is invalid"),
+	("--help", "", "Print this help and exit"),
+	("--version", "", "Print the version and exit"),
+];
+
+/// converts a config field name to its flag, e.g. docker_socket -> --docker-socket
+pub fn flag_name(field: &str) -> String {
+	format!("--{}", field.replace('_', "-"))
+}
+
+/// the config fields that can be set with a flag, which are the ones that can be set with an env var
+fn flag_fields() -> impl Iterator<Item = &'static Field> {
+	CspyConfig::META
+		.fields
+		.iter()
+		.filter(|f| matches!(f.kind, FieldKind::Leaf { env: Some(_), .. }))
+}
+
+/// whether a field is a bool, which can be given as a bare flag.
+/// strings take any value and other types reject "true", so only bools accept "true" but reject anything else
+fn is_bool(field: &Field) -> bool {
+	parse_layer([(field.name, "true")]).is_ok() && parse_layer([(field.name, "-")]).is_err()
+}
+
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<Cli> {
+	let mut cli = Cli {
+		action: Action::Run,
+		config_path: None,
+		flags: BTreeMap::new(),
+	};
+
+	// skip argv[0]
+	let mut args = args.into_iter().skip(1).peekable();
+
+	while let Some(arg) = args.next() {
+		// support both --flag value and --flag=value
+		let (flag, inline_val) = match arg.split_once('=') {
+			Some((f, v)) if f.starts_with("--") => (f.to_owned(), Some(v.to_owned())),
+			_ => (arg, None),
+		};
+
+		// a following flag is never taken as a value, so a missing value is reported as such
+		let mut value = |flag: &str| {
+			inline_val
+				.clone()
+				.or_else(|| args.next_if(|a| !a.starts_with("--")))
+				.ok_or_else(|| anyhow!("{flag} requires a value"))
+		};
+
+		match flag.as_str() {
+			"--help" | "-h" => cli.action = Action::Help,
+			"--version" | "-V" => cli.action = Action::Version,
+			"--print-config" => cli.action = Action::PrintConfig,
+			"--check-config" => cli.action = Action::CheckConfig,
+			"healthcheck" => cli.action = Action::Healthcheck,
+			"--config" => cli.config_path = Some(value(&flag)?),
+			_ => {
+				let Some(field) = flag_fields().find(|f| flag_name(f.name) == flag) else {
+					bail!("Unknown argument {flag}, see --help for usage")
+				};
+
+				let val = if inline_val.is_none() && is_bool(field) {
+					// a bare bool flag means true, and only a bool is taken as its value
+					args.next_if(|a| parse_layer([(field.name, a.as_str())]).is_ok())
+						.unwrap_or_else(|| "true".to_owned())
+				} else {
+					value(&flag)?
+				};
+
+				cli.flags.insert(field.name, val);
+			}
+		}
+	}
+
+	Ok(cli)
+}
+
+impl Cli {
+	/// deserializes the flags into a config layer
+	pub fn config_layer(&self) -> Result<<CspyConfig as Config>::Partial, ConfigError> {
+		parse_layer(self.flags.iter().map(|(k, v)| (*k, v.as_str()))).map_err(|e| {
+			let key = e.path().to_string();
+			ConfigError::new(e.into_inner(), Some(key.clone()), format!("flag {}", flag_name(&key)))
+		})
+	}
+}
+
+/// deserializes a config layer from raw strings keyed by config field name, as given in flags or env vars
+pub fn parse_layer<'a>(
+	values: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Result<<CspyConfig as Config>::Partial, serde_path_to_error::Error<DeError>> {
+	let de = de::value::MapDeserializer::<_, DeError>::new(values.into_iter().map(|(k, v)| (k, FlagValue(v))));
+
+	serde_path_to_error::deserialize(de)
+}
+
+pub fn help_text() -> String {
+	let flags: Vec<_> = flag_fields()
+		.map(|f| {
+			let arg = if is_bool(f) { "[<bool>]" } else { "<value>" };
+			(format!("{} {arg}", flag_name(f.name)), f)
+		})
+		.collect();
+
+	// wide enough for the longest entry in each column, plus a gap
+	let flag_w = flags.iter().map(|(flag, _)| flag.len()).max().unwrap_or_default() + 2;
+	let name_w = flags.iter().map(|(_, f)| f.name.len()).max().unwrap_or_default() + 2;
+	let env_w = flag_fields()
+		.filter_map(|f| match f.kind {
+			FieldKind::Leaf { env, .. } => env,
+			_ => None,
+		})
+		.map(str::len)
+		.max()
+		.unwrap_or_default()
+		+ 2;
+
+	let mut out = format!("Usage: {} [healthcheck] [options]\n\nCommands:\n", built_info::PKG_NAME);
+	out += &format!(
+		"  {:<flag_w$}{}\n",
+		"healthcheck", "Check /healthz on a running containerspy (needs http_listen), for Docker HEALTHCHECK"
+	);
+	out += "\nOptions:\n";
+
+	for (flag, arg, desc) in CLI_FLAGS {
+		out += &format!("  {:<flag_w$}{desc}\n", format!("{flag} {arg}"));
+	}
+
+	out += "\nConfig options (flags override env vars, which override the config file):\n\n";
+	out += &format!("  {:<flag_w$}{:<name_w$}{:<env_w$}{}\n", "flag", "config file", "env var", "description");
+
+	for (flag, field) in &flags {
+		let FieldKind::Leaf { env: Some(env), .. } = field.kind else {
+			continue;
+		};
+
+		let doc = field.doc.iter().map(|line| line.trim()).collect::<Vec<_>>().join(" ");
+		out += &format!("  {flag:<flag_w$}{:<name_w$}{env:<env_w$}{doc}\n", field.name);
+	}
+
+	include_str!("help.txt")
+		.trim_end()
+		.replace("{{REPO_URL}}", built_info::PKG_REPOSITORY)
+		.replace("{{OPTIONS}}", out.trim_end())
+}
+
+/// a raw string from the command line or an env var that can be deserialized into any config field type,
+/// parsing numbers and bools as necessary, like confique does for env vars
+struct FlagValue<'a>(&'a str);
+
+impl<'de> IntoDeserializer<'de, DeError> for FlagValue<'_> {
+	type Deserializer = Self;
+
+	fn into_deserializer(self) -> Self {
+		self
+	}
+}
+
+macro_rules! deser_parsed {
+	($($method:ident => $visit:ident),* $(,)?) => {
+		$(
+			fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+				match self.0.trim().parse() {
+					Ok(v) => visitor.$visit(v),
+					Err(_) => Err(de::Error::invalid_value(de::Unexpected::Str(self.0), &visitor)),
+				}
+			}
+		)*
+	};
+}
+
+impl<'de> de::Deserializer<'de> for FlagValue<'_> {
+	type Error = DeError;
+
+	fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+		visitor.visit_str(self.0)
+	}
+
+	fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+		visitor.visit_some(self)
+	}
+
+	fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+		// comma separated lists, an empty flag is an empty list
+		let items = self.0.split(',').map(str::trim).filter(|s| !s.is_empty());
+		visitor.visit_seq(de::value::SeqDeserializer::new(items.map(FlagValue)))
+	}
+
+	fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+		// same as confique's env var parsing
+		match self.0.trim().to_ascii_lowercase().as_str() {
+			"1" | "true" | "yes" => visitor.visit_bool(true),
+			"0" | "false" | "no" => visitor.visit_bool(false),
+			_ => Err(de::Error::invalid_value(de::Unexpected::Str(self.0), &visitor)),
+		}
+	}
+
+	fn deserialize_enum<V: Visitor<'de>>(
+		self,
+		_name: &'static str,
+		_variants: &'static [&'static str],
+		visitor: V,
+	) -> Result<V::Value, DeError> {
+		visitor.visit_enum(self.0.into_deserializer())
+	}
+
+	deser_parsed! {
+		deserialize_i8 => visit_i8,
+		deserialize_i16 => visit_i16,
+		deserialize_i32 => visit_i32,
+		deserialize_i64 => visit_i64,
+		deserialize_u8 => visit_u8,
+		deserialize_u16 => visit_u16,
+		deserialize_u32 => visit_u32,
+		deserialize_u64 => visit_u64,
+		deserialize_f32 => visit_f32,
+		deserialize_f64 => visit_f64,
+	}
+
+	forward_to_deserialize_any! {
+		i128 u128 char str string bytes byte_buf unit unit_struct newtype_struct tuple
+		tuple_struct map struct identifier ignored_any
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use confique::Partial;
+
+	fn args(list: &[&str]) -> Vec<String> {
+		std::iter::once("containerspy").chain(list.iter().copied()).map(str::to_owned).collect()
+	}
+
+	fn flags(list: &[&str]) -> Vec<(&'static str, String)> {
+		parse(args(list)).unwrap().flags.into_iter().collect()
+	}
+
+	fn config(list: &[&str]) -> CspyConfig {
+		let layer = parse(args(list)).unwrap().config_layer().unwrap_or_else(|e| panic!("{e}"));
+		CspyConfig::from_partial(layer.with_fallback(Partial::default_values())).unwrap()
+	}
+
+	#[test]
+	fn parses_flags() {
+		assert_eq!(
+			flags(&["--docker-socket", "/tmp/docker.sock", "--otlp-endpoint=localhost:1234"]),
+			[("docker_socket", "/tmp/docker.sock".to_owned()), ("otlp_endpoint", "localhost:1234".to_owned())]
+		);
+
+		let cli = parse(args(&["healthcheck", "--config", "cspy.json"])).unwrap();
+		assert!(matches!(cli.action, Action::Healthcheck));
+		assert_eq!(cli.config_path.as_deref(), Some("cspy.json"));
+
+		// values are never taken from the next flag
+		assert!(parse(args(&["--docker-socket", "--print-config"])).is_err());
+		assert!(parse(args(&["--config"])).is_err());
+		assert!(parse(args(&["--not-a-flag"])).is_err());
+	}
+
+	#[test]
+	fn parses_bool_flags() {
+		// bare, before another flag, before a command, and with a value
+		let cli = parse(args(&["--otlp-logs", "--print-config"])).unwrap();
+		assert!(matches!(cli.action, Action::PrintConfig));
+		assert_eq!(cli.flags.into_iter().collect::<Vec<_>>(), [("otlp_logs", "true".to_owned())]);
+
+		assert_eq!(flags(&["--otlp-logs", "healthcheck"]), [("otlp_logs", "true".to_owned())]);
+		assert_eq!(flags(&["--otlp-logs"]), [("otlp_logs", "true".to_owned())]);
+		assert_eq!(flags(&["--otlp-logs", "no"]), [("otlp_logs", "no".to_owned())]);
+		assert_eq!(flags(&["--otlp-logs=false"]), [("otlp_logs", "false".to_owned())]);
+
+		assert!(!config(&["--label-sanitize", "0"]).label_sanitize);
+		assert!(config(&["--otlp-logs"]).otlp_logs);
+	}
+
+	#[test]
+	fn config_file_only_options_have_no_flag() {
+		assert!(parse(args(&["--label-relabel", "[]"])).is_err());
+		assert!(!help_text().contains("--label-relabel"));
+	}
+
+	#[test]
+	fn flag_layer_parses_values() {
+		let cfg = config(&["--docker-hosts", "unix:///a.sock, ssh://b", "--docker-timeout", "5", "--label-allow", ""]);
+		assert_eq!(cfg.docker_hosts, ["unix:///a.sock", "ssh://b"]);
+		assert_eq!(cfg.docker_timeout, 5);
+		assert!(cfg.label_allow.is_empty());
+
+		let Err(err) = parse(args(&["--docker-timeout", "soon"])).unwrap().config_layer() else {
+			panic!("--docker-timeout soon should not parse");
+		};
+		assert!(err.to_string().contains("--docker-timeout"), "{err}");
+	}
+
+	#[test]
+	fn help_columns_are_separated() {
+		let help = help_text();
+
+		for field in flag_fields() {
+			let FieldKind::Leaf { env: Some(env), .. } = field.kind else {
+				continue;
+			};
+
+			let line = help.lines().find(|l| l.contains(&flag_name(field.name))).unwrap();
+			assert!(line.contains(&format!(" {}  ", field.name)), "{line}");
+			assert!(line.contains(&format!(" {env}  ")), "{line}");
+		}
+	}
+
+	#[test]
+	fn help_joins_wrapped_descriptions() {
+		let help = help_text();
+		let line = help.lines().find(|l| l.contains("--docker-hosts")).unwrap();
+
+		assert!(line.contains("context names. Replaces docker_socket"), "{line}");
+	}
+}
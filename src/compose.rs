@@ -0,0 +1,153 @@
+// labels for containers that are part of a docker compose project
+
+use std::collections::HashMap;
+
+use crate::metrics::Labels;
+
+const PROJECT: &str = "com.docker.compose.project";
+const SERVICE: &str = "com.docker.compose.service";
+const CONTAINER_NUMBER: &str = "com.docker.compose.container-number";
+const WORKING_DIR: &str = "com.docker.compose.project.working_dir";
+
+/// the compose project, service, and replica a container is, from the labels compose puts on its containers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Container {
+	pub project: String,
+	pub service: Option<String>,
+	/// which of the service's containers this is, e.g. 2 for the second replica
+	pub replica: Option<String>,
+	/// where the project's compose file is, to tell apart projects with the same name
+	pub working_dir: Option<String>,
+}
+
+impl Container {
+	pub fn from_labels(labels: &HashMap<String, String>) -> Option<Container> {
+		Some(Container {
+			project: labels.get(PROJECT)?.clone(),
+			service: labels.get(SERVICE).cloned(),
+			replica: labels.get(CONTAINER_NUMBER).cloned(),
+			working_dir: labels.get(WORKING_DIR).cloned(),
+		})
+	}
+
+	/// the attributes identifying this container in its project
+	pub fn labels(&self) -> Labels {
+		let mut out = Labels::default();
+		out.push("compose_project", "docker.compose.project", self.project.clone());
+		if let Some(service) = &self.service {
+			out.push("compose_service", "docker.compose.service", service.clone());
+		}
+		if let Some(replica) = &self.replica {
+			out.push("compose_replica", "docker.compose.replica", replica.clone());
+		}
+		if let Some(working_dir) = &self.working_dir {
+			out.push("compose_working_dir", "docker.compose.working_dir", working_dir.clone());
+		}
+		out
+	}
+
+	/// the attributes for the per-project totals
+	pub fn project_labels(&self, host: Option<&str>) -> Labels {
+		let mut out = Labels::default();
+		out.push("compose_project", "docker.compose.project", self.project.clone());
+		if let Some(host) = host {
+			out.push("docker_host", "docker.host", host.to_owned());
+		}
+		out
+	}
+
+	/// the attributes for the per-service totals, if the container has a service
+	pub fn service_labels(&self, host: Option<&str>) -> Option<Labels> {
+		let service = self.service.as_ref()?;
+		let mut out = self.project_labels(host);
+		out.push("compose_service", "docker.compose.service", service.clone());
+		Some(out)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn labels(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+		pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+	}
+
+	fn cadvisor(labels: &Labels) -> Vec<(String, String)> {
+		labels.cadvisor.iter().map(|kv| (kv.key.to_string(), kv.value.to_string())).collect()
+	}
+
+	#[test]
+	fn parses_containers() {
+		let web = labels(&[
+			(PROJECT, "shop"),
+			(SERVICE, "web"),
+			(CONTAINER_NUMBER, "2"),
+			(WORKING_DIR, "/srv/shop"),
+			("com.docker.compose.config-hash", "8f2c"),
+		]);
+		assert_eq!(
+			Container::from_labels(&web),
+			Some(Container {
+				project: "shop".to_owned(),
+				service: Some("web".to_owned()),
+				replica: Some("2".to_owned()),
+				working_dir: Some("/srv/shop".to_owned()),
+			})
+		);
+
+		// only the project is needed, e.g. for containers from `docker compose run`
+		let bare = Container::from_labels(&labels(&[(PROJECT, "shop")])).unwrap();
+		assert_eq!((bare.service, bare.replica, bare.working_dir), (None, None, None));
+
+		assert_eq!(Container::from_labels(&labels(&[(SERVICE, "web"), (CONTAINER_NUMBER, "1")])), None);
+		assert_eq!(Container::from_labels(&labels(&[("com.docker.swarm.service.name", "web")])), None);
+	}
+
+	#[test]
+	fn labels_containers() {
+		let web = Container::from_labels(&labels(&[
+			(PROJECT, "shop"),
+			(SERVICE, "web"),
+			(CONTAINER_NUMBER, "2"),
+			(WORKING_DIR, "/srv/shop"),
+		]))
+		.unwrap();
+
+		assert_eq!(
+			cadvisor(&web.labels()),
+			[
+				("compose_project".to_owned(), "shop".to_owned()),
+				("compose_service".to_owned(), "web".to_owned()),
+				("compose_replica".to_owned(), "2".to_owned()),
+				("compose_working_dir".to_owned(), "/srv/shop".to_owned()),
+			]
+		);
+		let semconv: Vec<_> = web.labels().semconv.iter().map(|kv| kv.key.to_string()).collect();
+		assert_eq!(
+			semconv,
+			["docker.compose.project", "docker.compose.service", "docker.compose.replica", "docker.compose.working_dir"]
+		);
+
+		let bare = Container::from_labels(&labels(&[(PROJECT, "shop")])).unwrap();
+		assert_eq!(cadvisor(&bare.labels()), [("compose_project".to_owned(), "shop".to_owned())]);
+	}
+
+	#[test]
+	fn labels_totals() {
+		let web = Container::from_labels(&labels(&[(PROJECT, "shop"), (SERVICE, "web")])).unwrap();
+
+		assert_eq!(cadvisor(&web.project_labels(None)), [("compose_project".to_owned(), "shop".to_owned())]);
+		assert_eq!(
+			cadvisor(&web.service_labels(Some("tcp://rack1:2375")).unwrap()),
+			[
+				("compose_project".to_owned(), "shop".to_owned()),
+				("docker_host".to_owned(), "tcp://rack1:2375".to_owned()),
+				("compose_service".to_owned(), "web".to_owned()),
+			]
+		);
+
+		let bare = Container::from_labels(&labels(&[(PROJECT, "shop")])).unwrap();
+		assert!(bare.service_labels(None).is_none());
+	}
+}
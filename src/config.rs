@@ -1,43 +1,581 @@
-use std::sync::LazyLock;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
 
 use anyhow::Result;
-use confique::Config;
+use confique::meta::{FieldKind, LeafKind};
+use confique::serde::de::{IgnoredAny, Unexpected};
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use confique::{Config, Partial};
 use opentelemetry_otlp::Protocol;
-use crate::s_log::*;
+use regex::Regex;
+use crate::cli::Cli;
+use crate::metrics;
+use crate::s_log::{self, *};
+
+/// exit code for an invalid config, EX_CONFIG from sysexits.h
+pub const EXIT_CONFIG: i32 = 78;
+
+type CspyPartial = <CspyConfig as Config>::Partial;
 
 #[derive(Config)]
 pub struct CspyConfig {
+	/// The docker socket / named pipe, or tcp:// or ssh:// address, to connect to (default: DOCKER_HOST, else the
+	/// current docker context, else /var/run/docker.sock or //./pipe/docker_engine)
 	#[config(env = "CSPY_DOCKER_SOCKET")]
 	pub docker_socket: Option<String>,
 
+	/// Several docker engines to watch at once, as sockets, tcp://, or ssh:// addresses, or docker context names.
+	/// Replaces docker_socket, and labels everything with the engine it came from (default: none)
+	#[config(env = "CSPY_DOCKER_HOSTS", parse_env = confique::env::parse::list_by_comma, default = [])]
+	pub docker_hosts: Vec<String>,
+
+	/// How long to wait for the Docker API to respond, in seconds (default: 60)
+	#[config(env = "CSPY_DOCKER_TIMEOUT", default = 60)]
+	pub docker_timeout: u64,
+
+	/// Whether to use httpbinary, httpjson, or grpc to send OTLP metrics (default: httpbinary)
 	#[config(env = "CSPY_OTLP_PROTO", default = "httpbinary", deserialize_with = crate::config::deser_protocol)]
 	pub otlp_protocol: Protocol,
 
+	/// Where to post metrics to (default: localhost:4318 for HTTP, localhost:4317 for gRPC)
 	#[config(env = "CSPY_OTLP_ENDPOINT")]
 	pub otlp_endpoint: Option<String>,
 
+	/// How often to report metrics, in milliseconds (default: OTEL_METRIC_EXPORT_INTERVAL or 60 seconds)
 	#[config(env = "CSPY_OTLP_INTERVAL")]
 	pub otlp_export_interval: Option<u64>,
+
+	/// Whether to also send containerspy's own logs via OTLP, using otlp_protocol (default: false)
+	#[config(env = "CSPY_OTLP_LOGS", default = false)]
+	pub otlp_logs: bool,
+
+	/// Where to post logs to (default: otlp_endpoint, with /v1/metrics replaced by /v1/logs for HTTP)
+	#[config(env = "CSPY_OTLP_LOGS_ENDPOINT")]
+	pub otlp_logs_endpoint: Option<String>,
+
+	/// How often to check the config file for changes and reload it, in milliseconds (default: only reload on SIGHUP)
+	#[config(env = "CSPY_CONFIG_WATCH_INTERVAL")]
+	pub config_watch_interval: Option<u64>,
+
+	/// Regexes for docker labels to export as container_label_* attributes, comma separated in env vars (default: all
+	/// labels)
+	#[config(env = "CSPY_LABEL_ALLOW", parse_env = confique::env::parse::list_by_comma, default = [])]
+	pub label_allow: Vec<Pattern>,
+
+	/// Regexes for docker labels to never export, takes priority over label_allow (default: none)
+	#[config(env = "CSPY_LABEL_DENY", parse_env = confique::env::parse::list_by_comma, default = [])]
+	pub label_deny: Vec<Pattern>,
+
+	/// Rules to rename docker labels, as a list of { regex, target }, config file only (default: none)
+	#[config(default = [])]
+	pub label_relabel: Vec<RelabelRule>,
+
+	/// Whether to replace characters that are not valid in Prometheus label names with underscores (default: true)
+	#[config(env = "CSPY_LABEL_SANITIZE", default = true)]
+	pub label_sanitize: bool,
+
+	/// Container env vars to export as container_env_* attributes, matched by prefix, comma separated in env vars
+	/// (default: none)
+	#[config(env = "CSPY_ENV_LABELS", parse_env = confique::env::parse::list_by_comma, default = [])]
+	pub env_labels: Vec<String>,
+
+	/// Whether to name metrics and attributes like cadvisor, like the OpenTelemetry semconv, or both (default: cadvisor)
+	#[config(env = "CSPY_METRIC_NAMING", default = "cadvisor")]
+	pub metric_naming: MetricNaming,
+
+	/// Whether to export CPU and memory utilization ratios, derived from the usage and the container's limits (default:
+	/// false)
+	#[config(env = "CSPY_UTILIZATION_METRICS", default = false)]
+	pub utilization_metrics: bool,
+
+	/// Whether to export the CPU and memory of each swarm service, summed over its tasks on this node (default: false)
+	#[config(env = "CSPY_SWARM_SERVICE_METRICS", default = false)]
+	pub swarm_service_metrics: bool,
+
+	/// Whether to export the CPU and memory of each compose project and service, summed over its containers (default:
+	/// false)
+	#[config(env = "CSPY_COMPOSE_METRICS", default = false)]
+	pub compose_metrics: bool,
+
+	/// Metric groups or individual metrics not to export, comma separated in env vars (default: none)
+	#[config(env = "CSPY_DISABLE_METRICS", parse_env = confique::env::parse::list_by_comma, default = [])]
+	pub disable_metrics: Vec<String>,
+
+	/// Path to the host's /proc, to find the docker network of each interface of containers on several networks
+	/// (default: none)
+	#[config(env = "CSPY_HOST_PROC")]
+	pub host_proc: Option<String>,
+
+	/// Address to serve /healthz, /readyz, and /debug/containers on, e.g. 127.0.0.1:9101 (default: disabled)
+	#[config(env = "CSPY_HTTP_LISTEN")]
+	pub http_listen: Option<SocketAddr>,
+
+	/// Whether to log as logfmt, json, or human (default: logfmt)
+	#[config(env = "CSPY_LOG_FORMAT", default = "logfmt")]
+	pub log_format: LogFormat,
+
+	/// Minimum level to log: fatal, error, warn, info, or debug (default: info)
+	#[config(env = "CSPY_LOG_LEVEL", default = "info")]
+	pub log_level: LogLevel,
+
+	/// Where to log: stdout, stderr, journald, or syslog (default: stdout)
+	#[config(env = "CSPY_LOG_OUTPUT", default = "stdout")]
+	pub log_output: LogOutput,
+
+	/// Where to send syslog logs: a unix socket path, or udp://host:port (default: /dev/log)
+	#[config(env = "CSPY_LOG_SYSLOG_ADDRESS", default = "/dev/log")]
+	pub log_syslog_address: String,
+
+	/// Minimum log levels for specific modules as module=level, overriding log_level, comma separated in env vars
+	/// (default: none)
+	#[config(env = "CSPY_LOG_MODULES", parse_env = confique::env::parse::list_by_comma, default = [])]
+	pub log_modules: Vec<ModuleLevel>,
+
+	/// Whether to forward container logs as OTLP logs (default: false)
+	#[config(env = "CSPY_CONTAINER_LOGS", default = false)]
+	pub container_logs: bool,
+
+	/// Regexes for names of containers to forward logs from, comma separated in env vars (default: all containers)
+	#[config(env = "CSPY_CONTAINER_LOGS_INCLUDE", parse_env = confique::env::parse::list_by_comma, default = [])]
+	pub container_logs_include: Vec<Pattern>,
+
+	/// Regexes for names of containers to never forward logs from, takes priority over container_logs_include (default:
+	/// none)
+	#[config(env = "CSPY_CONTAINER_LOGS_EXCLUDE", parse_env = confique::env::parse::list_by_comma, default = [])]
+	pub container_logs_exclude: Vec<Pattern>,
+
+	/// Regex for lines that start a new log record, other lines are joined onto the previous one (default: every line is
+	/// a record)
+	#[config(env = "CSPY_CONTAINER_LOGS_MULTILINE")]
+	pub container_logs_multiline: Option<Pattern>,
+
+	/// Maximum log records per second to forward from each container, the rest are dropped (default: unlimited)
+	#[config(env = "CSPY_CONTAINER_LOGS_RATE_LIMIT")]
+	pub container_logs_rate_limit: Option<u64>,
+
+	/// File to store how far each container's logs have been forwarded, to resume from after a restart (default: none)
+	#[config(env = "CSPY_CONTAINER_LOGS_STATE_FILE")]
+	pub container_logs_state_file: Option<String>,
+
+	/// Whether to export Docker events as OTLP logs and a container_events_total metric (default: false)
+	#[config(env = "CSPY_CONTAINER_EVENTS", default = false)]
+	pub container_events: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricNaming {
+	/// cAdvisor compatible names, e.g. container_cpu_usage_seconds_total
+	Cadvisor,
+	/// OpenTelemetry semantic conventions, e.g. container.cpu.time
+	Semconv,
+	/// both of the above, for migrating between them
+	Both,
+}
+
+/// a regex that must match the whole string, like in Prometheus relabel configs
+#[derive(Clone)]
+pub struct Pattern(Regex);
+
+impl Pattern {
+	pub fn regex(&self) -> &Regex {
+		&self.0
+	}
+
+	pub fn is_match(&self, s: &str) -> bool {
+		self.0.is_match(s)
+	}
+}
+
+impl FromStr for Pattern {
+	type Err = regex::Error;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		// check the regex by itself first so that errors point at the user's input, not at our anchors
+		Regex::new(s)?;
+		Regex::new(&format!("^(?:{s})$")).map(Pattern)
+	}
+}
+
+impl PartialEq for Pattern {
+	fn eq(&self, other: &Self) -> bool {
+		self.0.as_str() == other.0.as_str()
+	}
+}
+
+impl std::fmt::Debug for Pattern {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		// strip the anchors we added
+		let s = self.0.as_str();
+		write!(f, "{:?}", &s[4..s.len() - 2])
+	}
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+	fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+		let s = String::deserialize(d)?;
+		s.parse().map_err(|e| D::Error::custom(format!("invalid regex {s:?}: {e}")))
+	}
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct RelabelRule {
+	/// matched against the docker label key
+	pub regex: Pattern,
+	/// the new label key, can use capture groups from the regex like $1. an empty target drops the label.
+	pub target: String,
+}
+
+impl CspyConfig {
+	/// every config value, formatted for logging
+	pub fn entries(&self) -> Vec<(&'static str, String)> {
+		vec![
+			("docker_socket", format!("{:?}", self.docker_socket)),
+			("docker_hosts", format!("{:?}", self.docker_hosts)),
+			("docker_timeout", format!("{:?}", self.docker_timeout)),
+			("otlp_protocol", format!("{:?}", self.otlp_protocol)),
+			("otlp_endpoint", format!("{:?}", self.otlp_endpoint)),
+			("otlp_export_interval", format!("{:?}", self.otlp_export_interval)),
+			("otlp_logs", format!("{:?}", self.otlp_logs)),
+			("otlp_logs_endpoint", format!("{:?}", self.otlp_logs_endpoint)),
+			("config_watch_interval", format!("{:?}", self.config_watch_interval)),
+			("label_allow", format!("{:?}", self.label_allow)),
+			("label_deny", format!("{:?}", self.label_deny)),
+			("label_relabel", format!("{:?}", self.label_relabel)),
+			("label_sanitize", format!("{:?}", self.label_sanitize)),
+			("env_labels", format!("{:?}", self.env_labels)),
+			("metric_naming", format!("{:?}", self.metric_naming)),
+			("utilization_metrics", format!("{:?}", self.utilization_metrics)),
+			("swarm_service_metrics", format!("{:?}", self.swarm_service_metrics)),
+			("compose_metrics", format!("{:?}", self.compose_metrics)),
+			("disable_metrics", format!("{:?}", self.disable_metrics)),
+			("host_proc", format!("{:?}", self.host_proc)),
+			("http_listen", format!("{:?}", self.http_listen)),
+			("log_format", format!("{:?}", self.log_format)),
+			("log_level", format!("{:?}", self.log_level)),
+			("log_output", format!("{:?}", self.log_output)),
+			("log_syslog_address", format!("{:?}", self.log_syslog_address)),
+			("log_modules", format!("{:?}", self.log_modules)),
+			("container_logs", format!("{:?}", self.container_logs)),
+			("container_logs_include", format!("{:?}", self.container_logs_include)),
+			("container_logs_exclude", format!("{:?}", self.container_logs_exclude)),
+			("container_logs_multiline", format!("{:?}", self.container_logs_multiline)),
+			("container_logs_rate_limit", format!("{:?}", self.container_logs_rate_limit)),
+			("container_logs_state_file", format!("{:?}", self.container_logs_state_file)),
+			("container_events", format!("{:?}", self.container_events)),
+		]
+	}
+
+	pub fn configure_logging(&self) {
+		s_log::configure(self.log_format, self.log_level, self.log_output, &self.log_syslog_address, &self.log_modules);
+	}
+
+	/// if the OTLP exporter needs rebuilding to go from this config to `other`
+	pub fn exporter_changed(&self, other: &CspyConfig) -> bool {
+		self.otlp_protocol != other.otlp_protocol
+			|| self.otlp_endpoint != other.otlp_endpoint
+			|| self.otlp_export_interval != other.otlp_export_interval
+	}
+
+	/// if the OTLP log exporter needs rebuilding to go from this config to `other`
+	pub fn logs_exporter_changed(&self, other: &CspyConfig) -> bool {
+		self.otlp_protocol != other.otlp_protocol
+			|| self.otlp_logs != other.otlp_logs
+			|| self.container_logs != other.container_logs
+			|| self.container_events != other.container_events
+			|| self.logs_endpoint() != other.logs_endpoint()
+	}
+
+	/// the endpoint to send logs to, if not the default
+	pub fn logs_endpoint(&self) -> Option<String> {
+		if let Some(e) = &self.otlp_logs_endpoint {
+			return Some(e.clone());
+		}
+
+		// HTTP endpoints are full URLs for the signal, but gRPC endpoints are shared by all signals
+		let e = self.otlp_endpoint.as_ref()?;
+		match self.otlp_protocol {
+			Protocol::Grpc => Some(e.clone()),
+			_ => e.strip_suffix("/v1/metrics").map(|base| format!("{base}/v1/logs")),
+		}
+	}
+
+	/// the engines to watch, as the docker_host label for their metrics, if watching several, and their address
+	pub fn engines(&self) -> Vec<(Option<String>, Option<String>)> {
+		if self.docker_hosts.is_empty() {
+			vec![(None, self.docker_socket.clone())]
+		} else {
+			self.docker_hosts.iter().map(|h| (Some(h.clone()), Some(h.clone()))).collect()
+		}
+	}
+
+	/// checks what deserializing can't: that disable_metrics only has metrics and groups that exist, so typos
+	/// don't silently disable nothing
+	fn validate(&self, cli: &Cli) -> Result<(), ConfigError> {
+		let Some(unknown) = self.disable_metrics.iter().find(|n| !metrics::is_known_name(n)) else {
+			return Ok(());
+		};
+
+		let source = sources(cli)
+			.ok()
+			.and_then(|mut s| s.remove("disable_metrics"))
+			.unwrap_or_else(|| "merged config".to_string());
+
+		let mut err = ConfigError::new(
+			format_args!("unknown metric or group {unknown:?}"),
+			Some("disable_metrics".to_string()),
+			source,
+		);
+		err.expected = Some("a metric group, or a cAdvisor or semconv metric name".to_string());
+		Err(err)
+	}
+
+	/// if running workers need restarting to pick up the changes from this config to `other`
+	pub fn workers_changed(&self, other: &CspyConfig) -> bool {
+		self.label_allow != other.label_allow
+			|| self.label_deny != other.label_deny
+			|| self.label_relabel != other.label_relabel
+			|| self.label_sanitize != other.label_sanitize
+			|| self.env_labels != other.env_labels
+			|| self.metric_naming != other.metric_naming
+			|| self.utilization_metrics != other.utilization_metrics
+			|| self.swarm_service_metrics != other.swarm_service_metrics
+			|| self.compose_metrics != other.compose_metrics
+			|| self.disable_metrics != other.disable_metrics
+			|| self.host_proc != other.host_proc
+	}
+
+	/// if running log forwarding workers need restarting to pick up the changes from this config to `other`.
+	/// changes to which containers are included are picked up without a restart.
+	pub fn log_workers_changed(&self, other: &CspyConfig) -> bool {
+		self.workers_changed(other)
+			|| self.container_logs_multiline != other.container_logs_multiline
+			|| self.container_logs_rate_limit != other.container_logs_rate_limit
+	}
+}
+
+/// an error loading the config, with enough detail to point the user at what to fix
+pub struct ConfigError {
+	pub msg: String,
+	/// the offending config key, if known
+	pub key: Option<String>,
+	/// where the bad value came from, e.g. "env CSPY_OTLP_PROTO" or "file /etc/containerspy/config.json"
+	pub source: String,
+	/// a description of valid values, if known
+	pub expected: Option<String>,
 }
 
-pub static CONFIG: LazyLock<CspyConfig> = LazyLock::new(|| {
-	let cfg_loc = std::env::var("CSPY_CONFIG");
-	let cfg_loc = cfg_loc
-		.as_deref()
-		.ok()
-		.unwrap_or("/etc/containerspy/config.json");
+impl ConfigError {
+	pub fn new(err: impl Display, key: Option<String>, source: String) -> Self {
+		let msg = err.to_string();
+		// serde errors are formatted as "invalid value: ..., expected ..."
+		let expected = msg.split_once(", expected ").map(|(_, e)| e.trim_end_matches('.').to_string());
 
-	let cfg = CspyConfig::builder().env().file(cfg_loc).load().unwrap();
+		// serde_path_to_error uses "." for the root
+		let key = key.filter(|k| k != ".");
 
-	info("Loaded config at startup", [
-		("docker_socket", &*format!("{:?}", cfg.docker_socket)),
-		("otlp_protocol", &*format!("{:?}", cfg.otlp_protocol)),
-		("otlp_endpoint", &*format!("{:?}", cfg.otlp_endpoint)),
-		("otlp_export_interval", &*format!("{:?}", cfg.otlp_export_interval)),
-	]);
-	
-	cfg
-});
+		ConfigError { msg, key, source, expected }
+	}
+
+	/// the error as rich log values
+	pub fn rich(&self) -> Vec<(&str, &str)> {
+		let mut rich = vec![("source", self.source.as_str())];
+
+		if let Some(key) = &self.key {
+			rich.push(("key", key));
+		}
+		if let Some(expected) = &self.expected {
+			rich.push(("expected", expected));
+		}
+
+		rich
+	}
+}
+
+impl Display for ConfigError {
+	fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+		match &self.key {
+			Some(key) => write!(f, "invalid value for {key} from {}: {}", self.source, self.msg),
+			None => write!(f, "invalid config from {}: {}", self.source, self.msg),
+		}
+	}
+}
+
+static CONFIG: RwLock<Option<Arc<CspyConfig>>> = RwLock::new(None);
+
+/// gets the current config. panics if called before `init`.
+/// the config may be swapped out by a reload at any time, so don't hold onto this for longer than necessary.
+pub fn current() -> Arc<CspyConfig> {
+	CONFIG
+		.read()
+		.unwrap()
+		.clone()
+		.expect("config accessed before it was loaded")
+}
+
+pub fn init(cfg: CspyConfig) {
+	cfg.configure_logging();
+
+	let entries = cfg.entries();
+	info("Loaded config at startup", entries.iter().map(|(k, v)| (*k, v.as_str())));
+
+	*CONFIG.write().unwrap() = Some(Arc::new(cfg));
+}
+
+/// reloads the config and swaps it in, returning the old config.
+/// if the new config is invalid, it is rejected and the old config is kept.
+pub fn reload(cli: &Cli) -> Result<Arc<CspyConfig>, ConfigError> {
+	let new_cfg = load(cli)?;
+	new_cfg.configure_logging();
+
+	let entries = new_cfg.entries();
+	info("Reloaded config", entries.iter().map(|(k, v)| (*k, v.as_str())));
+
+	let old = CONFIG.write().unwrap().replace(Arc::new(new_cfg));
+
+	Ok(old.expect("config reloaded before it was loaded"))
+}
+
+/// gets the modification time of the config file, for watching for changes
+pub fn config_mtime(path: &str) -> Option<SystemTime> {
+	std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// finds the config file: --config, then CSPY_CONFIG, then ./config.json if it exists, then the global config
+pub fn config_path(cli: &Cli) -> String {
+	if let Some(path) = &cli.config_path {
+		return path.clone();
+	}
+
+	if let Ok(path) = std::env::var("CSPY_CONFIG") {
+		return path;
+	}
+
+	if Path::new("config.json").exists() {
+		"config.json".to_string()
+	} else {
+		"/etc/containerspy/config.json".to_string()
+	}
+}
+
+/// loads the config from (in order of priority) flags, env vars, the config file, and defaults
+pub fn load(cli: &Cli) -> Result<CspyConfig, ConfigError> {
+	let path = config_path(cli);
+
+	let layer = cli
+		.config_layer()?
+		.with_fallback(load_env_layer()?)
+		.with_fallback(load_file_layer(&path)?)
+		.with_fallback(CspyPartial::default_values());
+
+	let cfg = CspyConfig::from_partial(layer).map_err(|e| ConfigError::new(e, None, "merged config".to_string()))?;
+	cfg.validate(cli)?;
+
+	Ok(cfg)
+}
+
+fn load_env_layer() -> Result<CspyPartial, ConfigError> {
+	CspyPartial::from_env().map_err(|e| {
+		// confique doesn't tell us which var was bad, so find it by parsing each one on its own
+		for field in CspyConfig::META.fields {
+			let FieldKind::Leaf { env: Some(env), .. } = field.kind else {
+				continue;
+			};
+			let Ok(val) = std::env::var(env) else {
+				continue;
+			};
+
+			if let Err(err) = crate::cli::parse_layer([(field.name, val.as_str())]) {
+				return ConfigError::new(err.into_inner(), Some(field.name.to_string()), format!("env {env}"));
+			}
+		}
+
+		ConfigError::new(e, None, "env".to_string())
+	})
+}
+
+fn load_file_layer(path: &str) -> Result<CspyPartial, ConfigError> {
+	let file_err = |err: &dyn Display, key: Option<String>| ConfigError::new(err, key, format!("file {path}"));
+
+	let src = match std::fs::read_to_string(path) {
+		Ok(s) => s,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(CspyPartial::empty()),
+		Err(e) => return Err(file_err(&e, None)),
+	};
+
+	let json5_err = |err: json5::Error, key: Option<String>| match err {
+		json5::Error::Message { msg, location: Some(loc) } => {
+			// syntax errors come with a multiline code snippet, but we only want the summary at the end
+			let msg = msg.lines().last().unwrap_or_default().trim().trim_start_matches("= ");
+			file_err(&format_args!("{msg} at line {}, column {}", loc.line, loc.column), key)
+		}
+		err => file_err(&err, key),
+	};
+
+	let mut de = json5::Deserializer::from_str(&src).map_err(|e| json5_err(e, None))?;
+	let layer: CspyPartial = serde_path_to_error::deserialize(&mut de).map_err(|e| {
+		let key = e.path().to_string();
+		json5_err(e.into_inner(), Some(key))
+	})?;
+
+	// confique silently ignores unknown keys, which hides typos
+	if let Ok(keys) = json5::from_str::<BTreeMap<String, IgnoredAny>>(&src) {
+		for key in keys.keys() {
+			if !CspyConfig::META.fields.iter().any(|f| f.name == key) {
+				warn(
+					format_args!("Unknown key {key} in config file, it will be ignored"),
+					[("key", key.as_str()), ("path", path)],
+				);
+			}
+		}
+	}
+
+	Ok(layer)
+}
+
+/// works out where each config value came from, for --print-config
+pub fn sources(cli: &Cli) -> Result<BTreeMap<&'static str, String>> {
+	let path = config_path(cli);
+
+	// we only care which keys are present here, confique does the actual parsing
+	let file_keys = match std::fs::read_to_string(&path) {
+		Ok(s) => json5::from_str::<BTreeMap<String, IgnoredAny>>(&s)?,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+		Err(e) => return Err(e.into()),
+	};
+
+	let mut out = BTreeMap::new();
+
+	for field in CspyConfig::META.fields {
+		let FieldKind::Leaf { env, kind } = field.kind else {
+			continue;
+		};
+
+		let env_val = env.and_then(|e| std::env::var(e).ok()).filter(|v| !v.is_empty());
+
+		let source = if cli.flags.contains_key(field.name) {
+			format!("flag {}", crate::cli::flag_name(field.name))
+		} else if let (Some(env), Some(_)) = (env, env_val) {
+			format!("env {env}")
+		} else if file_keys.contains_key(field.name) {
+			format!("file {path}")
+		} else if let LeafKind::Required { default: Some(_) } = kind {
+			"default".to_string()
+		} else {
+			"unset".to_string()
+		};
+
+		out.insert(field.name, source);
+	}
+
+	Ok(out)
+}
 
 /// deserialization boilerplate
 struct ProtoDeserVisitor;
@@ -47,7 +585,7 @@ impl confique::serde::de::Visitor<'_> for ProtoDeserVisitor {
 	type Value = Protocol;
 
 	fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-		formatter.write_str(r#""httpbinary", "httpjson", or "grpc"."#)
+		formatter.write_str(r#""httpbinary", "httpjson", or "grpc""#)
 	}
 
 	fn visit_str<E>(self, v: &str) -> std::result::Result<Self::Value, E>
@@ -58,11 +596,7 @@ impl confique::serde::de::Visitor<'_> for ProtoDeserVisitor {
 			"httpbinary" => Protocol::HttpBinary,
 			"httpjson" => Protocol::HttpJson,
 			"grpc" => Protocol::Grpc,
-			&_ => {
-				return Err(E::custom(format!(
-					"{v} is not a valid OTLP protocol, valid options are httpbinary, httpjson, or grpc."
-				)))
-			}
+			&_ => return Err(E::invalid_value(Unexpected::Str(v), &self)),
 		})
 	}
 }
@@ -0,0 +1,534 @@
+// works out where a docker engine is and connects to it, like the docker CLI does: unix sockets, tcp with or without
+// TLS, ssh, DOCKER_HOST, and docker contexts
+
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher, RandomState};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use bollard::system::Version;
+use bollard::{ClientVersion, Docker, API_DEFAULT_VERSION};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::s_log::*;
+use crate::self_metrics;
+
+/// the oldest API version docker supports, to ask engines that are too old for ours which version they support
+const MIN_API_VERSION: &ClientVersion = &ClientVersion { major_version: 1, minor_version: 24 };
+
+#[cfg(unix)]
+const DEFAULT_SOCKET: &str = "unix:///var/run/docker.sock";
+#[cfg(windows)]
+const DEFAULT_SOCKET: &str = "npipe:////./pipe/docker_engine";
+
+/// a docker client, plus the ssh tunnel it goes through, if any
+pub struct Client {
+	pub docker: Docker,
+	transport: Transport,
+	/// in seconds
+	timeout: u64,
+	tunnel: Option<Tunnel>,
+}
+
+impl Client {
+	/// restarts the ssh tunnel if it has exited, e.g. because the connection dropped, and waits for it to be up.
+	/// returns whether it was restarted, as any streams over it will have ended
+	pub async fn ensure_tunnel(&mut self) -> bool {
+		match &mut self.tunnel {
+			Some(tunnel) => tunnel.ensure().await,
+			None => false,
+		}
+	}
+
+	/// asks the engine for its version, and switches to its API version if that's older than ours. engines reject
+	/// requests for newer API versions than they support, even for their version, so those are asked with the oldest
+	pub async fn negotiate_version(&mut self) -> Result<Version> {
+		let version = match self_metrics::time_docker("version", self.docker.version()).await {
+			Err(bollard::errors::Error::DockerResponseServerError { status_code: 400, .. }) => {
+				let oldest = self.transport.connect(self.timeout, MIN_API_VERSION)?;
+				self_metrics::time_docker("version", oldest.version()).await?
+			}
+			res => res?,
+		};
+
+		let wanted = negotiated_version(version.api_version.as_deref());
+		if wanted != self.docker.client_version() {
+			self.docker = self.transport.connect(self.timeout, &wanted)?;
+		}
+
+		Ok(version)
+	}
+}
+
+/// the engine's API version if it's older than ours, else ours, including if the engine's can't be parsed
+fn negotiated_version(api_version: Option<&str>) -> ClientVersion {
+	match api_version.and_then(parse_api_version) {
+		Some(v) if v < *API_DEFAULT_VERSION => v,
+		_ => *API_DEFAULT_VERSION,
+	}
+}
+
+fn parse_api_version(version: &str) -> Option<ClientVersion> {
+	let (major, minor) = version.split_once('.')?;
+	Some(ClientVersion {
+		major_version: major.parse().ok()?,
+		minor_version: minor.parse().ok()?,
+	})
+}
+
+/// how to connect to the engine, kept to reconnect with a different API version
+enum Transport {
+	Socket(String),
+	Http(String),
+	/// the host, and the directory with the client certificates
+	Ssl(String, PathBuf),
+}
+
+impl Transport {
+	fn connect(&self, timeout: u64, version: &ClientVersion) -> Result<Docker> {
+		Ok(match self {
+			Transport::Socket(path) => Docker::connect_with_socket(path, timeout, version)?,
+			Transport::Http(host) => Docker::connect_with_http(host, timeout, version)?,
+			Transport::Ssl(host, certs) => Docker::connect_with_ssl(
+				host,
+				&certs.join("key.pem"),
+				&certs.join("cert.pem"),
+				&certs.join("ca.pem"),
+				timeout,
+				version,
+			)?,
+		})
+	}
+}
+
+/// where to connect to, and the client certificates to use if it's over TLS
+struct Endpoint {
+	host: String,
+	tls: Option<PathBuf>,
+}
+
+/// connects to `address`, or if it isn't set, to wherever the docker CLI would: DOCKER_HOST, else the current docker
+/// context, else the default docker socket, or podman's if there isn't one. `timeout` is in seconds.
+/// this doesn't talk to the engine yet, so only fails if the address or context is invalid, or an ssh tunnel to it
+/// can't be opened.
+pub async fn connect(address: Option<&str>, timeout: u64) -> Result<Client> {
+	let endpoint = match address {
+		Some(addr) => Some(Endpoint {
+			host: addr.to_owned(),
+			tls: env_tls(),
+		}),
+		None => default_endpoint()?,
+	};
+
+	connect_endpoint(endpoint, timeout).await
+}
+
+/// connects to one of several hosts, given as an address or the name of a docker context. the TLS env vars are for
+/// one engine, so only contexts, which have their own certificates, can use TLS here
+pub async fn connect_host(entry: &str, timeout: u64) -> Result<Client> {
+	let endpoint = if is_context_name(entry) {
+		load_context(entry)?
+	} else {
+		Endpoint {
+			host: entry.to_owned(),
+			tls: None,
+		}
+	};
+
+	connect_endpoint(Some(endpoint), timeout).await
+}
+
+/// like the docker CLI's context names, which addresses can't be as they have a scheme or a path
+fn is_context_name(name: &str) -> bool {
+	name.starts_with(|c: char| c.is_ascii_alphanumeric())
+		&& name.chars().all(|c| c.is_ascii_alphanumeric() || "_.+-".contains(c))
+}
+
+async fn connect_endpoint(endpoint: Option<Endpoint>, timeout: u64) -> Result<Client> {
+	let mut tunnel = None;
+
+	let transport = match endpoint {
+		None => Transport::Socket(podman_socket().unwrap_or_else(|| DEFAULT_SOCKET.to_owned())),
+		Some(Endpoint { host, .. }) if host.starts_with("ssh://") => {
+			let mut t = Tunnel::new(&host["ssh://".len()..])?;
+
+			// the client checks the socket exists, so this waits for ssh to create it. the watcher restarts it later
+			if !t.ensure().await {
+				bail!("Failed to open an ssh tunnel to {}", t.destination);
+			}
+			let path = t.local.to_string_lossy().into_owned();
+			tunnel = Some(t);
+			Transport::Socket(path)
+		}
+		Some(Endpoint { host, tls }) => {
+			let tcp = ["tcp://", "http://", "https://"].iter().any(|s| host.starts_with(s));
+			match tls {
+				Some(dir) if tcp => Transport::Ssl(host, dir),
+				_ if host.starts_with("https://") => bail!("{host} needs TLS, but has no client certificates"),
+				_ if tcp => Transport::Http(host),
+				_ => Transport::Socket(host),
+			}
+		}
+	};
+
+	Ok(Client {
+		docker: transport.connect(timeout, API_DEFAULT_VERSION)?,
+		transport,
+		timeout,
+		tunnel,
+	})
+}
+
+/// DOCKER_HOST, else the endpoint of the current context, unless that's the default context
+fn default_endpoint() -> Result<Option<Endpoint>> {
+	if let Some(host) = std::env::var("DOCKER_HOST").ok().filter(|h| !h.is_empty()) {
+		return Ok(Some(Endpoint { host, tls: env_tls() }));
+	}
+
+	match current_context() {
+		Some(name) if name != "default" => load_context(&name).map(Some),
+		_ => Ok(None),
+	}
+}
+
+/// the certificate directory, if DOCKER_TLS_VERIFY is set
+fn env_tls() -> Option<PathBuf> {
+	std::env::var("DOCKER_TLS_VERIFY").ok().filter(|v| !v.is_empty())?;
+
+	match std::env::var_os("DOCKER_CERT_PATH").filter(|p| !p.is_empty()) {
+		Some(path) => Some(path.into()),
+		None => docker_config_dir(),
+	}
+}
+
+/// ~/.docker, or DOCKER_CONFIG
+fn docker_config_dir() -> Option<PathBuf> {
+	if let Some(dir) = std::env::var_os("DOCKER_CONFIG").filter(|d| !d.is_empty()) {
+		return Some(dir.into());
+	}
+
+	let home = std::env::var_os(if cfg!(windows) { "USERPROFILE" } else { "HOME" })?;
+	Some(Path::new(&home).join(".docker"))
+}
+
+/// DOCKER_CONTEXT, else the currentContext in the docker CLI's config.json
+fn current_context() -> Option<String> {
+	if let Some(name) = std::env::var("DOCKER_CONTEXT").ok().filter(|c| !c.is_empty()) {
+		return Some(name);
+	}
+
+	#[derive(Deserialize)]
+	struct CliConfig {
+		#[serde(rename = "currentContext")]
+		current_context: Option<String>,
+	}
+
+	let config = std::fs::read_to_string(docker_config_dir()?.join("config.json")).ok()?;
+	let config: CliConfig = serde_json::from_str(&config).ok()?;
+	config.current_context.filter(|c| !c.is_empty())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContextMeta {
+	endpoints: HashMap<String, ContextEndpoint>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ContextEndpoint {
+	host: String,
+	#[serde(rename = "SkipTLSVerify", default)]
+	skip_tls_verify: bool,
+}
+
+/// reads a context from the docker CLI's context store, where each context is in a directory named by its name's
+/// sha256, with its TLS material separately
+fn load_context(name: &str) -> Result<Endpoint> {
+	let dir = docker_config_dir().ok_or_else(|| anyhow!("Can't find the docker config directory"))?.join("contexts");
+	let id = format!("{:x}", Sha256::digest(name));
+
+	let meta_path = dir.join("meta").join(&id).join("meta.json");
+	let meta = std::fs::read_to_string(&meta_path).with_context(|| format!("Failed to read docker context {name}"))?;
+	let meta: ContextMeta =
+		serde_json::from_str(&meta).with_context(|| format!("Failed to parse {}", meta_path.display()))?;
+
+	let endpoint = meta
+		.endpoints
+		.get("docker")
+		.ok_or_else(|| anyhow!("Docker context {name} has no docker endpoint"))?;
+
+	if endpoint.skip_tls_verify {
+		warn(
+			format_args!("Docker context {name} skips TLS verification, which isn't supported, so it will be verified"),
+			[("context", name)],
+		);
+	}
+
+	let tls = dir.join("tls").join(&id).join("docker");
+	debug(format_args!("Using docker context {name}"), [("context", name), ("host", &*endpoint.host)]);
+
+	Ok(Endpoint {
+		host: endpoint.host.clone(),
+		tls: tls.join("ca.pem").exists().then_some(tls),
+	})
+}
+
+/// podman's socket, rootless or not, on hosts without docker
+fn podman_socket() -> Option<String> {
+	if cfg!(windows) || Path::new("/var/run/docker.sock").exists() {
+		return None;
+	}
+
+	let rootless = std::env::var("XDG_RUNTIME_DIR").ok().map(|dir| format!("{dir}/podman/podman.sock"));
+	rootless
+		.into_iter()
+		.chain(["/run/podman/podman.sock".to_owned()])
+		.find(|path| Path::new(path).exists())
+}
+
+/// forwards a local socket to the engine's socket on a remote host, with the ssh client, so it uses the same keys,
+/// agent, and ~/.ssh/config as the docker CLI would
+struct Tunnel {
+	/// [user@]host
+	destination: String,
+	port: Option<String>,
+	remote: String,
+	/// in a directory only we can use, so nobody else can put something there first or talk to the engine through it
+	local: PathBuf,
+	child: Option<Child>,
+}
+
+impl Tunnel {
+	/// from the part of an ssh:// address after the scheme, [user@]host[:port][/path/to/remote.sock]
+	fn new(address: &str) -> Result<Tunnel> {
+		if cfg!(windows) {
+			bail!("Connecting to docker over ssh is not supported on Windows");
+		}
+
+		let (destination, port, remote) = parse_ssh_address(address)?;
+
+		Ok(Tunnel {
+			destination,
+			port: port.map(str::to_owned),
+			remote: remote.to_owned(),
+			local: private_dir()?.join("docker.sock"),
+			child: None,
+		})
+	}
+
+	async fn ensure(&mut self) -> bool {
+		if let Some(child) = &mut self.child {
+			match child.try_wait() {
+				Ok(None) => return false,
+				Ok(Some(status)) => warn(
+					format_args!("ssh tunnel to {} exited with {status}, restarting it", self.destination),
+					[("destination", &*self.destination)],
+				),
+				Err(e) => warn(
+					format_args!("Failed to check on the ssh tunnel to {}, restarting it: {e}", self.destination),
+					[("destination", &*self.destination)],
+				),
+			}
+		}
+
+		self.stop();
+		let _ = std::fs::remove_file(&self.local);
+
+		let mut cmd = Command::new("ssh");
+		cmd.args(["-nNT", "-o", "BatchMode=yes", "-o", "ExitOnForwardFailure=yes", "-o", "StreamLocalBindUnlink=yes"])
+			.arg("-L")
+			.arg(format!("{}:{}", self.local.display(), self.remote))
+			.stdin(Stdio::null())
+			.stdout(Stdio::null());
+		if let Some(port) = &self.port {
+			cmd.args(["-p", port]);
+		}
+		cmd.arg(&self.destination);
+
+		match cmd.spawn() {
+			Ok(child) => self.child = Some(child),
+			Err(e) => {
+				error(
+					format_args!("Failed to start ssh for the tunnel to {}: {e}", self.destination),
+					[("destination", &*self.destination)],
+				);
+				return false;
+			}
+		}
+
+		// ssh creates the socket once it has connected, or exits if it can't
+		for _ in 0..50 {
+			if is_socket(&self.local) {
+				debug(format_args!("Opened ssh tunnel to {}", self.destination), [("destination", &*self.destination)]);
+				return true;
+			}
+			if self.child.as_mut().is_some_and(|c| matches!(c.try_wait(), Ok(Some(_)))) {
+				return false;
+			}
+			tokio::time::sleep(Duration::from_millis(100)).await;
+		}
+
+		false
+	}
+
+	fn stop(&mut self) {
+		if let Some(mut child) = self.child.take() {
+			let _ = child.kill();
+			let _ = child.wait();
+		}
+	}
+}
+
+impl Drop for Tunnel {
+	fn drop(&mut self) {
+		self.stop();
+		let _ = std::fs::remove_file(&self.local);
+		if let Some(dir) = self.local.parent() {
+			let _ = std::fs::remove_dir(dir);
+		}
+	}
+}
+
+/// splits [user@]host[:port][/path/to/remote.sock] into the destination, port, and remote socket
+fn parse_ssh_address(address: &str) -> Result<(String, Option<&str>, &str)> {
+	let (authority, remote) = match address.find('/') {
+		Some(i) => (&address[..i], &address[i..]),
+		None => (address, "/var/run/docker.sock"),
+	};
+
+	// the port is after the last colon, unless that's inside a bracketed IPv6 address
+	let (destination, port) = match authority.rsplit_once(':') {
+		Some((dest, port)) if !port.contains(']') => (dest, Some(port)),
+		_ => (authority, None),
+	};
+
+	// ssh takes IPv6 addresses without the brackets that separate them from the port
+	let destination = destination.replace(['[', ']'], "");
+	if destination.is_empty() || destination.ends_with('@') {
+		bail!("ssh://{address} has no host");
+	}
+
+	Ok((destination, port, remote))
+}
+
+/// makes a new directory only we can use, in XDG_RUNTIME_DIR if there is one as that's already private, else the temp
+/// directory. creating it fails if anything is already there, so the name being guessable doesn't matter
+fn private_dir() -> Result<PathBuf> {
+	let base = std::env::var_os("XDG_RUNTIME_DIR")
+		.filter(|d| !d.is_empty())
+		.map_or_else(std::env::temp_dir, PathBuf::from);
+
+	let mut builder = std::fs::DirBuilder::new();
+	#[cfg(unix)]
+	std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+
+	for _ in 0..10 {
+		let dir = base.join(format!("containerspy-{:016x}", RandomState::new().build_hasher().finish()));
+		match builder.create(&dir) {
+			Ok(()) => return Ok(dir),
+			Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+			Err(e) => return Err(e).with_context(|| format!("Failed to create {}", dir.display())),
+		}
+	}
+
+	bail!("Failed to create a directory for the ssh tunnel's socket in {}", base.display())
+}
+
+#[cfg(unix)]
+fn is_socket(path: &Path) -> bool {
+	use std::os::unix::fs::FileTypeExt;
+	std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket())
+}
+
+#[cfg(not(unix))]
+fn is_socket(_path: &Path) -> bool {
+	false
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn tells_context_names_from_addresses() {
+		assert!(is_context_name("rack1"));
+		assert!(is_context_name("prod-eu.2"));
+		assert!(!is_context_name("/var/run/docker.sock"));
+		assert!(!is_context_name("unix:///var/run/docker.sock"));
+		assert!(!is_context_name("tcp://rack1:2376"));
+		assert!(!is_context_name("ssh://user@rack1"));
+		assert!(!is_context_name("-rack1"));
+		assert!(!is_context_name(""));
+	}
+
+	fn version(major_version: usize, minor_version: usize) -> ClientVersion {
+		ClientVersion { major_version, minor_version }
+	}
+
+	#[test]
+	fn parses_api_versions() {
+		assert_eq!(parse_api_version("1.41"), Some(version(1, 41)));
+		assert_eq!(parse_api_version("1.24"), Some(version(1, 24)));
+		assert_eq!(parse_api_version("2.0"), Some(version(2, 0)));
+	}
+
+	#[test]
+	fn rejects_malformed_api_versions() {
+		for bad in ["", "1", "1.", ".41", "v1.41", "1.41.0", "1.x", " 1.41"] {
+			assert_eq!(parse_api_version(bad), None, "{bad:?}");
+		}
+	}
+
+	#[test]
+	fn uses_older_engine_api_versions() {
+		assert_eq!(negotiated_version(Some("1.41")), version(1, 41));
+		assert_eq!(negotiated_version(Some("1.24")), version(1, 24));
+	}
+
+	#[test]
+	fn uses_our_api_version_for_newer_or_unknown_engine_versions() {
+		let ours = *API_DEFAULT_VERSION;
+		let newer = format!("{}.{}", ours.major_version, ours.minor_version + 1);
+
+		assert_eq!(negotiated_version(Some(&newer)), ours);
+		assert_eq!(negotiated_version(Some(&format!("{}.{}", ours.major_version, ours.minor_version))), ours);
+		assert_eq!(negotiated_version(Some("")), ours);
+		assert_eq!(negotiated_version(Some("garbage")), ours);
+		assert_eq!(negotiated_version(None), ours);
+	}
+
+	#[test]
+	fn parses_ssh_addresses() {
+		let parse = |address| parse_ssh_address(address).unwrap();
+
+		assert_eq!(parse("rack1"), ("rack1".to_owned(), None, "/var/run/docker.sock"));
+		assert_eq!(parse("me@rack1"), ("me@rack1".to_owned(), None, "/var/run/docker.sock"));
+		assert_eq!(parse("me@rack1:2222"), ("me@rack1".to_owned(), Some("2222"), "/var/run/docker.sock"));
+		assert_eq!(
+			parse("me@rack1:2222/run/podman/podman.sock"),
+			("me@rack1".to_owned(), Some("2222"), "/run/podman/podman.sock")
+		);
+		assert_eq!(parse("rack1/run/docker.sock"), ("rack1".to_owned(), None, "/run/docker.sock"));
+	}
+
+	#[test]
+	fn parses_ssh_addresses_with_ipv6_hosts() {
+		let parse = |address| parse_ssh_address(address).unwrap();
+
+		assert_eq!(parse("[::1]"), ("::1".to_owned(), None, "/var/run/docker.sock"));
+		assert_eq!(parse("me@[fe80::1]:2222"), ("me@fe80::1".to_owned(), Some("2222"), "/var/run/docker.sock"));
+		assert_eq!(parse("[2001:db8::2]:22/run/docker.sock"), ("2001:db8::2".to_owned(), Some("22"), "/run/docker.sock"));
+	}
+
+	#[test]
+	fn rejects_ssh_addresses_without_a_host() {
+		assert!(parse_ssh_address("").is_err());
+		assert!(parse_ssh_address(":2222").is_err());
+		assert!(parse_ssh_address("me@").is_err());
+		assert!(parse_ssh_address("/var/run/docker.sock").is_err());
+	}
+}
@@ -0,0 +1,176 @@
+// detects which container engine is behind the socket, and smooths over the differences in their docker-compatible APIs
+
+use anyhow::Result;
+use bollard::container::{CPUStats, Stats};
+use bollard::models::SystemInfo;
+use bollard::system::Version;
+use bollard::Docker;
+
+use crate::docker_host::Client;
+use crate::s_log::*;
+use crate::self_metrics;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Engine {
+	Docker,
+	Podman,
+}
+
+impl Engine {
+	/// the value of the semconv container.runtime attribute
+	pub fn runtime(self) -> &'static str {
+		match self {
+			Engine::Docker => "docker",
+			Engine::Podman => "podman",
+		}
+	}
+
+	fn from_version(version: &Version) -> Engine {
+		let components = version.components.iter().flatten();
+
+		if components.map(|c| &c.name).any(|n| n.starts_with("Podman")) {
+			Engine::Podman
+		} else {
+			Engine::Docker
+		}
+	}
+}
+
+/// a client for one engine
+pub struct Connection {
+	pub docker: Docker,
+	pub engine: Engine,
+	/// the address this engine was configured with, when watching several, to add to everything we export about it
+	pub host: Option<String>,
+}
+
+/// asks the engine what it is, settles on an API version with it, and logs what we're connected to
+pub async fn detect(client: &mut Client, host: &str) -> Result<Engine> {
+	let version = client.negotiate_version().await?;
+	let engine = Engine::from_version(&version);
+
+	// only for the log, so not worth failing over
+	let system = match self_metrics::time_docker("info", client.docker.info()).await {
+		Ok(system) => system,
+		Err(err) => {
+			warn(format_args!("Failed to get engine info: {err}"), [("docker_host", host)]);
+			SystemInfo::default()
+		}
+	};
+
+	let v = version.version.unwrap_or_default();
+	info(
+		format_args!("Connected to {} {v}", engine.runtime()),
+		[
+			("engine", engine.runtime()),
+			("version", &*v),
+			("api_version", &*client.docker.client_version().to_string()),
+			("max_api_version", &*version.api_version.unwrap_or_default()),
+			("os", &*system.operating_system.unwrap_or_default()),
+			("cgroup_version", &*system.cgroup_version.map(|c| c.to_string()).unwrap_or_default()),
+			("cgroup_driver", &*system.cgroup_driver.map(|c| c.to_string()).unwrap_or_default()),
+			("docker_host", host),
+		],
+	);
+
+	Ok(engine)
+}
+
+/// turns a stream of stats from any engine into what docker would have sent
+pub struct StatsAdapter {
+	engine: Engine,
+	last_cpu: Option<CPUStats>,
+}
+
+impl StatsAdapter {
+	pub fn new(engine: Engine) -> Self {
+		StatsAdapter { engine, last_cpu: None }
+	}
+
+	pub fn adapt(&mut self, mut stats: Stats) -> Stats {
+		if self.engine != Engine::Podman {
+			return stats;
+		}
+
+		// podman doesn't reliably fill in precpu_stats, so use the cpu_stats of the last read instead
+		let last_cpu = self.last_cpu.replace(stats.cpu_stats.clone());
+		stats.precpu_stats = last_cpu.unwrap_or_else(|| stats.cpu_stats.clone());
+
+		// podman passes through runc style entries, capitalised, and with totals on cgroups v1, where docker only has
+		// read and write
+		if let Some(entries) = &mut stats.blkio_stats.io_service_bytes_recursive {
+			for entry in entries.iter_mut() {
+				entry.op.make_ascii_lowercase();
+			}
+			entries.retain(|e| e.op == "read" || e.op == "write");
+		}
+
+		stats
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// payloads in the shape podman 4.9's docker-compatible API sends them, for a container on a cgroups v2 host
+	const PODMAN_VERSION: &str = include_str!("../testdata/podman_version.json");
+	const PODMAN_STATS: &str = include_str!("../testdata/podman_stats.jsonl");
+	const DOCKER_VERSION: &str = include_str!("../testdata/docker_version.json");
+
+	fn podman_stats() -> Vec<Stats> {
+		PODMAN_STATS.lines().map(|l| serde_json::from_str(l).unwrap()).collect()
+	}
+
+	#[test]
+	fn detects_engine() {
+		assert_eq!(Engine::from_version(&serde_json::from_str(PODMAN_VERSION).unwrap()), Engine::Podman);
+		assert_eq!(Engine::from_version(&serde_json::from_str(DOCKER_VERSION).unwrap()), Engine::Docker);
+	}
+
+	#[test]
+	fn fills_in_precpu() {
+		let mut adapter = StatsAdapter::new(Engine::Podman);
+		let stats: Vec<_> = podman_stats().into_iter().map(|s| adapter.adapt(s)).collect();
+
+		// the first read has nothing to diff against
+		assert_eq!(stats[0].precpu_stats, stats[0].cpu_stats);
+
+		for pair in stats.windows(2) {
+			assert_eq!(pair[1].precpu_stats, pair[0].cpu_stats);
+			assert!(pair[1].cpu_stats.cpu_usage.total_usage >= pair[1].precpu_stats.cpu_usage.total_usage);
+			assert!(pair[1].cpu_stats.system_cpu_usage >= pair[1].precpu_stats.system_cpu_usage);
+		}
+	}
+
+	#[test]
+	fn normalises_blkio() {
+		let mut adapter = StatsAdapter::new(Engine::Podman);
+		let stats = adapter.adapt(podman_stats().remove(1));
+
+		let entries = stats.blkio_stats.io_service_bytes_recursive.unwrap();
+		let ops: Vec<_> = entries.iter().map(|e| e.op.as_str()).collect();
+		assert_eq!(ops, ["read", "write"]);
+		assert_eq!(entries[0].value, 4481024);
+	}
+
+	#[test]
+	fn keeps_podman_networks_and_memory() {
+		let stats = StatsAdapter::new(Engine::Podman).adapt(podman_stats().remove(0));
+
+		// keyed by the interface in the container's namespace, like docker
+		let networks = stats.networks.unwrap();
+		assert_eq!(networks.keys().collect::<Vec<_>>(), ["eth0"]);
+		assert_eq!(networks["eth0"].rx_bytes, 8774);
+
+		// the compat API has no memory.stat breakdown, only usage and limit
+		assert!(stats.memory_stats.stats.is_none());
+		assert_eq!(stats.memory_stats.usage, Some(1990656));
+	}
+
+	#[test]
+	fn leaves_docker_alone() {
+		let stats = podman_stats().remove(1);
+		assert_eq!(StatsAdapter::new(Engine::Docker).adapt(stats.clone()), stats);
+	}
+}
@@ -0,0 +1,233 @@
+// exports docker events as OTLP log records and a container_events_total counter
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bollard::models::{ContainerSummary, EventMessage, EventMessageTypeEnum};
+use bollard::system::EventsOptions;
+use opentelemetry::logs::{AnyValue, LogRecord, Logger, Severity};
+use opentelemetry::metrics::Meter;
+use opentelemetry_sdk::logs::SdkLogger;
+use tokio::task::JoinHandle;
+use tokio_stream::StreamExt;
+
+use crate::config::MetricNaming;
+use crate::engine::Connection;
+use crate::metrics::Labels;
+use crate::s_log::*;
+use crate::{config, labels, metrics};
+
+/// the events we export, anything else docker sends is ignored
+const CONTAINER_EVENTS: &[&str] = &[
+	"create",
+	"start",
+	"restart",
+	"stop",
+	"die",
+	"kill",
+	"oom",
+	"pause",
+	"unpause",
+	"health_status",
+	"destroy",
+];
+const IMAGE_EVENTS: &[&str] = &["pull"];
+
+/// actor attributes that docker adds itself, the rest of a container's actor attributes are its labels
+const DOCKER_ATTRIBUTES: &[&str] = &["name", "image", "exitCode", "signal", "execDuration"];
+
+/// how long to wait before reconnecting if the event stream ends
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+pub fn launch_events_task(
+	conn: Arc<Connection>,
+	meter: Arc<Meter>,
+	logger: Option<Arc<SdkLogger>>,
+) -> JoinHandle<()> {
+	tokio::spawn(async move {
+		let config = config::current();
+		let naming = config.metric_naming;
+		let disabled = config.disable_metrics.clone();
+		drop(config);
+
+		let meter_container_events_total = metrics::builder(&meter, naming)
+			.disable(&disabled)
+			.cadvisor("container_events_total", "", "Number of Docker events for the container")
+			.semconv("container.events", "{events}", "Number of Docker events for the container", [])
+			.u64_counter();
+
+		// so that events for the same container don't each need an inspect when env_labels is set
+		let mut label_cache: HashMap<String, Labels> = HashMap::new();
+
+		// time of the last event we saw, to pick up where we left off after reconnecting.
+		// docker only takes whole seconds, so events from that second are skipped by their ns time
+		let mut since: Option<i64> = None;
+		let mut last_nano = 0;
+
+		loop {
+			let mut filters = HashMap::new();
+			filters.insert("type", vec!["container", "image"]);
+			filters.insert("event", CONTAINER_EVENTS.iter().chain(IMAGE_EVENTS).copied().collect());
+
+			let mut events = conn.docker.events(Some(EventsOptions {
+				since: since.map(|s| s.to_string()),
+				until: None,
+				filters,
+			}));
+
+			while let Some(event) = events.next().await {
+				let event = match event {
+					Ok(e) => e,
+					Err(err) => {
+						error(format_args!("Failed to read Docker events: {err:?}"), []);
+						break;
+					}
+				};
+
+				if let Some(nano) = event.time_nano {
+					if nano <= last_nano {
+						continue;
+					}
+					last_nano = nano;
+				}
+				if let Some(time) = event.time {
+					since = Some(time);
+				}
+
+				let Some(ev) = Event::parse(event) else {
+					continue;
+				};
+
+				let labels = match ev.typ {
+					EventMessageTypeEnum::CONTAINER => container_labels(&ev, &conn, &mut label_cache).await,
+					_ => {
+						let mut labels = Labels::default();
+						labels.push("image", "container.image.name", ev.id.clone());
+						if let Some(host) = &conn.host {
+							labels.push("docker_host", "docker.host", host.clone());
+						}
+						labels
+					}
+				};
+
+				let event_labels = labels.with("event", "event.name", ev.action.clone());
+				meter_container_events_total.record(1, &event_labels);
+
+				if let Some(logger) = &logger {
+					emit(logger, &ev, &event_labels, naming);
+				}
+			}
+
+			tokio::time::sleep(RECONNECT_DELAY).await;
+		}
+	})
+}
+
+/// the parts of an event we use
+struct Event {
+	typ: EventMessageTypeEnum,
+	/// e.g. die, or health_status without the status
+	action: String,
+	/// the container id, or image for image events
+	id: String,
+	attributes: HashMap<String, String>,
+	time_nano: Option<i64>,
+}
+
+impl Event {
+	fn parse(event: EventMessage) -> Option<Self> {
+		let actor = event.actor?;
+		let mut attributes = actor.attributes.unwrap_or_default();
+
+		// some actions have details after a colon, e.g. "health_status: healthy", which we split into an attribute
+		let action = event.action?;
+		let action = match action.split_once(':') {
+			Some((action, detail)) => {
+				attributes.insert(action.to_owned(), detail.trim().to_owned());
+				action.to_owned()
+			}
+			None => action,
+		};
+
+		Some(Event {
+			typ: event.typ?,
+			action,
+			id: actor.id?,
+			attributes,
+			time_nano: event.time_nano,
+		})
+	}
+}
+
+/// the same labels as the container's metrics, built from the event, as the container may not exist anymore
+async fn container_labels(ev: &Event, conn: &Connection, cache: &mut HashMap<String, Labels>) -> Labels {
+	if let Some(labels) = cache.get(&ev.id) {
+		let labels = labels.clone();
+		if ev.action == "destroy" {
+			cache.remove(&ev.id);
+		}
+		return labels;
+	}
+
+	let summary = ContainerSummary {
+		id: Some(ev.id.clone()),
+		names: ev.attributes.get("name").map(|n| vec![format!("/{n}")]),
+		image: ev.attributes.get("image").cloned(),
+		labels: Some(
+			ev.attributes
+				.iter()
+				.filter(|(k, _)| !DOCKER_ATTRIBUTES.contains(&k.as_str()) && !CONTAINER_EVENTS.contains(&k.as_str()))
+				.map(|(k, v)| (k.clone(), v.clone()))
+				.collect(),
+		),
+		..Default::default()
+	};
+
+	// env vars aren't in events, so need an inspect, while the container still exists
+	let config = config::current();
+	let details = if config.env_labels.is_empty() { None } else { labels::inspect(&conn.docker, &ev.id).await };
+	let labels = labels::container_attributes(&summary, details.as_ref(), conn, &config);
+
+	if ev.action != "destroy" {
+		cache.insert(ev.id.clone(), labels.clone());
+	}
+
+	labels
+}
+
+fn emit(logger: &SdkLogger, ev: &Event, labels: &Labels, naming: MetricNaming) {
+	let exit_code = ev.attributes.get("exitCode").filter(|c| *c != "0");
+
+	let unhealthy = ev.attributes.get("health_status").is_some_and(|s| s == "unhealthy");
+	let (severity, severity_text) = match ev.action.as_str() {
+		"oom" => (Severity::Error, "ERROR"),
+		"kill" => (Severity::Warn, "WARN"),
+		"die" if exit_code.is_some() => (Severity::Warn, "WARN"),
+		"health_status" if unhealthy => (Severity::Warn, "WARN"),
+		_ => (Severity::Info, "INFO"),
+	};
+
+	let typ = if ev.typ == EventMessageTypeEnum::IMAGE { "image" } else { "container" };
+
+	let mut record = logger.create_log_record();
+	if let Some(ns) = ev.time_nano {
+		record.set_timestamp(UNIX_EPOCH + Duration::from_nanos(ns.max(0) as u64));
+	}
+	record.set_observed_timestamp(SystemTime::now());
+	record.set_severity_number(severity);
+	record.set_severity_text(severity_text);
+	record.set_body(AnyValue::from(format!("{typ} {}", ev.action)));
+
+	let attrs = if naming == MetricNaming::Semconv { &labels.semconv } else { &labels.cadvisor };
+	record.add_attributes(attrs.iter().map(|kv| (kv.key.clone(), kv.value.to_string())));
+
+	// the details of the event that aren't already labels
+	for (key, attr) in [("exitCode", "exit_code"), ("signal", "signal"), ("health_status", "health_status")] {
+		if let Some(v) = ev.attributes.get(key) {
+			record.add_attribute(attr, v.clone());
+		}
+	}
+
+	logger.emit(record);
+}
@@ -0,0 +1,208 @@
+// optional http server for health checks and debugging, plus the client side of `containerspy healthcheck`
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+use crate::s_log::*;
+
+/// the discovery loop of each docker engine we're watching
+static HOSTS: Mutex<BTreeMap<String, HostStatus>> = Mutex::new(BTreeMap::new());
+
+static EXPORTED: AtomicBool = AtomicBool::new(false);
+
+static CONTAINERS: Mutex<BTreeMap<String, ContainerStatus>> = Mutex::new(BTreeMap::new());
+
+#[derive(Default)]
+struct HostStatus {
+	/// unix time in ms that the loop last ran, and how often it is expected to run
+	last_tick: u64,
+	tick_interval: u64,
+	reachable: bool,
+}
+
+#[derive(Clone, Serialize)]
+struct ContainerStatus {
+	id: String,
+	name: Option<String>,
+	/// unix time in seconds of the last stats sample
+	last_sample: Option<u64>,
+	last_error: Option<String>,
+}
+
+fn now_ms() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+/// marks the loop for a host as alive, given how long until it is next expected to run
+pub fn tick(host: &str, interval: Duration) {
+	let mut hosts = HOSTS.lock().unwrap();
+	let status = hosts.entry(host.to_owned()).or_default();
+	status.last_tick = now_ms();
+	status.tick_interval = interval.as_millis() as u64;
+}
+
+pub fn docker_reachable(host: &str, reachable: bool) {
+	HOSTS.lock().unwrap().entry(host.to_owned()).or_default().reachable = reachable;
+}
+
+/// stops tracking a host that is no longer watched
+pub fn host_removed(host: &str) {
+	HOSTS.lock().unwrap().remove(host);
+}
+
+/// stops tracking hosts that aren't configured anymore, including ones that were never connected to
+pub fn retain_hosts(hosts: &[&str]) {
+	HOSTS.lock().unwrap().retain(|h, _| hosts.contains(&h.as_str()));
+}
+
+pub fn exported() {
+	EXPORTED.store(true, Ordering::Relaxed);
+}
+
+pub fn container_started(id: &str, name: Option<String>) {
+	CONTAINERS.lock().unwrap().insert(
+		id.to_owned(),
+		ContainerStatus {
+			id: id.to_owned(),
+			name,
+			last_sample: None,
+			last_error: None,
+		},
+	);
+}
+
+pub fn container_sampled(id: &str) {
+	if let Some(c) = CONTAINERS.lock().unwrap().get_mut(id) {
+		c.last_sample = Some(now_ms() / 1000);
+	}
+}
+
+pub fn container_error(id: &str, err: String) {
+	if let Some(c) = CONTAINERS.lock().unwrap().get_mut(id) {
+		c.last_error = Some(err);
+	}
+}
+
+pub fn container_stopped(id: &str) {
+	CONTAINERS.lock().unwrap().remove(id);
+}
+
+fn is_healthy() -> bool {
+	let hosts = HOSTS.lock().unwrap();
+	let now = now_ms();
+
+	// allow for a couple of slow or missed ticks, as list_containers can take a while on a busy host
+	!hosts.is_empty()
+		&& hosts
+			.values()
+			.all(|h| h.last_tick != 0 && now.saturating_sub(h.last_tick) <= (h.tick_interval * 3).max(30_000))
+}
+
+fn is_ready() -> bool {
+	let hosts = HOSTS.lock().unwrap();
+	!hosts.is_empty() && hosts.values().all(|h| h.reachable) && EXPORTED.load(Ordering::Relaxed)
+}
+
+fn respond(path: &str) -> (&'static str, &'static str, String) {
+	let status = |ok: bool| if ok { ("200 OK", "ok\n") } else { ("503 Service Unavailable", "unavailable\n") };
+
+	match path {
+		"/healthz" => {
+			let (code, body) = status(is_healthy());
+			(code, "text/plain", body.to_owned())
+		}
+		"/readyz" => {
+			let (code, body) = status(is_ready());
+			(code, "text/plain", body.to_owned())
+		}
+		"/debug/containers" => {
+			let containers: Vec<_> = CONTAINERS.lock().unwrap().values().cloned().collect();
+			("200 OK", "application/json", serde_json::to_string(&containers).unwrap())
+		}
+		_ => ("404 Not Found", "text/plain", "not found\n".to_owned()),
+	}
+}
+
+pub fn spawn_server(addr: SocketAddr) -> JoinHandle<()> {
+	tokio::spawn(async move {
+		let listener = match TcpListener::bind(addr).await {
+			Ok(l) => l,
+			Err(e) => {
+				error(format_args!("Failed to start HTTP server on {addr}: {e}"), [("addr", &*addr.to_string())]);
+				return;
+			}
+		};
+
+		info(format_args!("Serving health checks on {addr}"), [("addr", &*addr.to_string())]);
+
+		loop {
+			let mut stream = match listener.accept().await {
+				Ok((stream, _)) => stream,
+				Err(e) => {
+					// errors like running out of file descriptors don't go away by themselves, so don't spin on them
+					warn(format_args!("Failed to accept HTTP connection: {e}"), [("addr", &*addr.to_string())]);
+					tokio::time::sleep(Duration::from_millis(100)).await;
+					continue;
+				}
+			};
+
+			tokio::spawn(async move {
+				// we only need the request line, so one read is plenty
+				let mut buf = [0; 1024];
+				let Ok(Ok(n)) = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await else {
+					return;
+				};
+
+				let req = String::from_utf8_lossy(&buf[..n]);
+				let mut parts = req.split_whitespace();
+				let (code, content_type, body) = match (parts.next(), parts.next()) {
+					(Some("GET"), Some(path)) => respond(path),
+					_ => ("405 Method Not Allowed", "text/plain", "method not allowed\n".to_owned()),
+				};
+
+				let res = format!(
+					"HTTP/1.1 {code}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\n\
+					Connection: close\r\n\r\n{body}",
+					body.len()
+				);
+				let _ = stream.write_all(res.as_bytes()).await;
+			});
+		}
+	})
+}
+
+/// checks /healthz on a running containerspy, for `containerspy healthcheck`, so images without curl can health check
+pub fn healthcheck(addr: SocketAddr) -> Result<(), String> {
+	// the server may listen on all interfaces, but we need a specific one to connect to
+	let addr = match addr.ip() {
+		IpAddr::V4(ip) if ip.is_unspecified() => SocketAddr::new(Ipv4Addr::LOCALHOST.into(), addr.port()),
+		IpAddr::V6(ip) if ip.is_unspecified() => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), addr.port()),
+		_ => addr,
+	};
+
+	let timeout = Duration::from_secs(5);
+	let mut stream =
+		TcpStream::connect_timeout(&addr, timeout).map_err(|e| format!("failed to connect to {addr}: {e}"))?;
+	stream.set_read_timeout(Some(timeout)).map_err(|e| e.to_string())?;
+
+	write!(stream, "GET /healthz HTTP/1.1\r\nHost: {addr}\r\nConnection: close\r\n\r\n").map_err(|e| e.to_string())?;
+
+	let mut res = String::new();
+	stream.read_to_string(&mut res).map_err(|e| e.to_string())?;
+
+	let status = res.lines().next().unwrap_or_default();
+	if status.split_whitespace().nth(1) == Some("200") {
+		Ok(())
+	} else {
+		Err(format!("unhealthy: {status}"))
+	}
+}
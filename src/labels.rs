@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use bollard::models::{ContainerInspectResponse, ContainerSummary};
+use bollard::Docker;
+use opentelemetry::KeyValue;
+use tokio::sync::OnceCell;
+
+use crate::config::CspyConfig;
+use crate::engine::Connection;
+use crate::metrics::Labels;
+use crate::s_log::*;
+use crate::{compose, self_metrics, swarm};
+
+/// the container's name, without docker's leading slash
+pub fn container_name(container: &ContainerSummary) -> Option<String> {
+	container
+		.names
+		.iter()
+		.flatten()
+		.next()
+		.map(|n| n.trim_start_matches("/").to_owned())
+}
+
+/// a container's inspect response, shared by its stats and log workers so that it is only fetched once
+pub type SharedInspect = Arc<OnceCell<Option<ContainerInspectResponse>>>;
+
+/// the container's inspect response, fetching it if no other worker has yet
+pub async fn inspect_once<'a>(
+	shared: &'a SharedInspect,
+	docker: &Docker,
+	container_id: &str,
+) -> Option<&'a ContainerInspectResponse> {
+	shared.get_or_init(|| inspect(docker, container_id)).await.as_ref()
+}
+
+pub async fn inspect(docker: &Docker, container_id: &str) -> Option<ContainerInspectResponse> {
+	match self_metrics::time_docker("inspect_container", docker.inspect_container(container_id, None)).await {
+		Ok(d) => Some(d),
+		Err(err) => {
+			warn(format_args!("Failed to inspect container {container_id}: {err:?}"), [("container_id", container_id)]);
+			None
+		}
+	}
+}
+
+/// builds the attributes shared by everything we export about a container.
+/// env vars aren't in the container summary, so env_labels are read from `details`, the container's inspect response.
+pub fn container_attributes(
+	container: &ContainerSummary,
+	details: Option<&ContainerInspectResponse>,
+	conn: &Connection,
+	config: &CspyConfig,
+) -> Labels {
+	let container_id = container.id.clone().unwrap_or_default();
+
+	let mut out = Labels::default();
+	out.push("id", "container.id", container_id.clone());
+	out.push(
+		"image",
+		"container.image.name",
+		container.image.clone().or_else(|| container.image_id.clone()).unwrap_or_default(),
+	);
+
+	if let Some(name) = container_name(container) {
+		out.push("name", "container.name", name);
+	}
+
+	out.semconv.push(KeyValue::new("container.runtime", conn.engine.runtime()));
+
+	if let Some(host) = &conn.host {
+		out.push("docker_host", "docker.host", host.clone());
+	}
+
+	if let Some(docker_labels) = &container.labels {
+		if let Some(task) = swarm::Task::from_labels(docker_labels) {
+			out.extend(task.labels());
+		}
+		if let Some(compose) = compose::Container::from_labels(docker_labels) {
+			out.extend(compose.labels());
+		}
+		out.extend(container_label_attributes(docker_labels, config));
+	}
+
+	if let Some(env) = details.and_then(|d| d.config.as_ref()).and_then(|c| c.env.as_ref()) {
+		out.extend(container_env_attributes(env, config));
+	}
+
+	out
+}
+
+/// builds the container_label_* (or container.label.* for semconv) attributes from a container's docker labels,
+/// applying the allow/deny lists, relabel rules, and sanitization from the config
+pub fn container_label_attributes(docker_labels: &HashMap<String, String>, config: &CspyConfig) -> Labels {
+	let mut out = Labels::default();
+
+	for (key, value) in docker_labels {
+		if config.label_deny.iter().any(|p| p.is_match(key)) {
+			continue;
+		}
+		if !config.label_allow.is_empty() && !config.label_allow.iter().any(|p| p.is_match(key)) {
+			continue;
+		}
+
+		let Some(key) = relabel(key, config) else {
+			continue;
+		};
+
+		push_prefixed(&mut out, "container_label_", "container.label.", &key, value, config);
+	}
+
+	out
+}
+
+/// builds the container_env_* (or container.env.* for semconv) attributes from a container's env vars
+/// (as KEY=value strings), for the vars that match a prefix in the env_labels config,
+/// like cAdvisor's --env_metadata_whitelist
+pub fn container_env_attributes(env: &[String], config: &CspyConfig) -> Labels {
+	let mut out = Labels::default();
+
+	for var in env {
+		let (key, value) = var.split_once('=').unwrap_or((var, ""));
+
+		if !config.env_labels.iter().any(|prefix| key.starts_with(prefix.as_str())) {
+			continue;
+		}
+
+		push_prefixed(&mut out, "container_env_", "container.env.", key, value, config);
+	}
+
+	out
+}
+
+fn push_prefixed(
+	out: &mut Labels,
+	cadvisor_prefix: &str,
+	semconv_prefix: &str,
+	key: &str,
+	value: &str,
+	config: &CspyConfig,
+) {
+	let cadvisor_key = cadvisor_prefix.to_string() + key;
+	let semconv_key = semconv_prefix.to_string() + key;
+
+	// only sanitize the part that came from the container for semconv, as the dots in the prefix are meant to be there
+	let (cadvisor_key, semconv_key) = if config.label_sanitize {
+		(sanitize_key(&cadvisor_key), semconv_prefix.to_string() + &sanitize_key(key))
+	} else {
+		(cadvisor_key, semconv_key)
+	};
+
+	out.cadvisor.push(KeyValue::new(cadvisor_key, value.to_string()));
+	out.semconv.push(KeyValue::new(semconv_key, value.to_string()));
+}
+
+/// applies the first matching relabel rule to a label key, returning None if it should be dropped
+fn relabel(key: &str, config: &CspyConfig) -> Option<String> {
+	for rule in &config.label_relabel {
+		if let Some(caps) = rule.regex.regex().captures(key) {
+			let mut target = String::new();
+			caps.expand(&rule.target, &mut target);
+
+			return if target.is_empty() { None } else { Some(target) };
+		}
+	}
+
+	Some(key.to_string())
+}
+
+/// replaces anything that isn't valid in a Prometheus label name with an underscore, like cAdvisor does
+pub fn sanitize_key(key: &str) -> String {
+	let mut out: String = key
+		.chars()
+		.map(|c| if c.is_ascii_alphanumeric() || c == '_' { c } else { '_' })
+		.collect();
+
+	if out.starts_with(|c: char| c.is_ascii_digit()) {
+		out.insert(0, '_');
+	}
+
+	out
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use confique::{Config, Partial};
+
+	fn config(json: &str) -> CspyConfig {
+		let layer: <CspyConfig as Config>::Partial = json5::from_str(json).unwrap();
+		CspyConfig::from_partial(layer.with_fallback(Partial::default_values())).unwrap()
+	}
+
+	/// the cadvisor keys exported for these docker labels, sorted as the labels come from a HashMap
+	fn label_keys(docker_labels: &[&str], config: &CspyConfig) -> Vec<String> {
+		let docker_labels = docker_labels.iter().map(|k| (k.to_string(), "v".to_owned())).collect();
+		let mut keys: Vec<_> = container_label_attributes(&docker_labels, config)
+			.cadvisor
+			.into_iter()
+			.map(|kv| kv.key.to_string())
+			.collect();
+		keys.sort();
+		keys
+	}
+
+	#[test]
+	fn relabels_keys() {
+		let cfg = config(
+			r#"{ label_relabel: [
+				{ regex: "com\\.example\\.(.*)", target: "example_$1" },
+				{ regex: "com\\.example\\.team", target: "never_used" },
+				{ regex: "org\\.opencontainers\\..*", target: "" },
+				{ regex: "(?<vendor>[a-z]+)\\.version", target: "${vendor}_ver" },
+			] }"#,
+		);
+
+		// captures are expanded, by number or name, and only the first matching rule applies
+		assert_eq!(relabel("com.example.team", &cfg).as_deref(), Some("example_team"));
+		assert_eq!(relabel("nginx.version", &cfg).as_deref(), Some("nginx_ver"));
+		// an empty target drops the label
+		assert_eq!(relabel("org.opencontainers.image.title", &cfg), None);
+		// labels no rule matches are kept as they are, and rules have to match the whole key
+		assert_eq!(relabel("app", &cfg).as_deref(), Some("app"));
+		assert_eq!(relabel("io.com.example.team", &cfg).as_deref(), Some("io.com.example.team"));
+	}
+
+	#[test]
+	fn filters_before_relabelling() {
+		// allow and deny match the original docker label, not the relabelled key
+		let cfg = config(
+			r#"{
+				label_allow: ["com\\.example\\..*"],
+				label_deny: [".*secret"],
+				label_relabel: [{ regex: "com\\.example\\.(.*)", target: "$1" }],
+			}"#,
+		);
+
+		assert_eq!(
+			label_keys(&["com.example.team", "com.example.secret", "team", "org.opencontainers.image.title"], &cfg),
+			["container_label_team"]
+		);
+	}
+
+	#[test]
+	fn sanitizes_keys() {
+		assert_eq!(sanitize_key("com.docker.compose.project"), "com_docker_compose_project");
+		assert_eq!(sanitize_key("app-name/v2"), "app_name_v2");
+		assert_eq!(sanitize_key("2fa"), "_2fa");
+		assert_eq!(sanitize_key("ünïcode"), "_n_code");
+		assert_eq!(sanitize_key("already_valid_1"), "already_valid_1");
+
+		// the semconv prefix keeps its dots, only the label part is sanitized
+		let docker_labels = [("1.app".to_owned(), "v".to_owned())].into();
+		let labels = container_label_attributes(&docker_labels, &config("{}"));
+		assert_eq!(labels.cadvisor[0].key.as_str(), "container_label_1_app");
+		assert_eq!(labels.semconv[0].key.as_str(), "container.label._1_app");
+
+		let labels = container_label_attributes(&docker_labels, &config("{ label_sanitize: false }"));
+		assert_eq!(labels.cadvisor[0].key.as_str(), "container_label_1.app");
+	}
+}
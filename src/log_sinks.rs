@@ -0,0 +1,321 @@
+// journald, syslog, and OTLP outputs for s_log
+
+use std::io;
+use std::net::UdpSocket;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::sync::RwLock;
+use std::time::SystemTime;
+
+use chrono::Utc;
+use opentelemetry::logs::{AnyValue, LogRecord, Logger, Severity};
+use opentelemetry::Key;
+use opentelemetry_sdk::logs::SdkLogger;
+
+use crate::built_info;
+use crate::s_log::LogLevel;
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// RFC 5424 facility for system daemons
+const FACILITY_DAEMON: u8 = 3;
+
+/// private enterprise number reserved for documentation by RFC 5612, as we don't have our own
+const SD_ID: &str = "cspy@32473";
+
+impl LogLevel {
+	/// syslog severity, which journald uses for PRIORITY too
+	fn severity(self) -> u8 {
+		match self {
+			LogLevel::Fatal => 2,
+			LogLevel::Error => 3,
+			LogLevel::Warn => 4,
+			LogLevel::Info => 6,
+			LogLevel::Debug => 7,
+		}
+	}
+
+	fn otel_severity(self) -> (Severity, &'static str) {
+		match self {
+			LogLevel::Fatal => (Severity::Fatal, "FATAL"),
+			LogLevel::Error => (Severity::Error, "ERROR"),
+			LogLevel::Warn => (Severity::Warn, "WARN"),
+			LogLevel::Info => (Severity::Info, "INFO"),
+			LogLevel::Debug => (Severity::Debug, "DEBUG"),
+		}
+	}
+}
+
+/// set when otlp_logs is enabled. logs are sent here as well as to the configured log output.
+static OTLP_LOGGER: RwLock<Option<SdkLogger>> = RwLock::new(None);
+
+pub fn set_otlp_logger(logger: Option<SdkLogger>) {
+	*OTLP_LOGGER.write().unwrap() = logger;
+}
+
+/// the first three pairs must be ts, level, and msg, the rest are sent as attributes
+pub fn send_otlp(level: LogLevel, module: &str, file: &str, line: u32, pairs: &[(&str, &str)]) {
+	let logger = OTLP_LOGGER.read().unwrap();
+	let Some(logger) = &*logger else {
+		return;
+	};
+
+	let (severity, severity_text) = level.otel_severity();
+
+	let mut record = logger.create_log_record();
+	record.set_timestamp(SystemTime::now());
+	record.set_severity_number(severity);
+	record.set_severity_text(severity_text);
+	record.set_target(module.to_owned());
+	record.set_body(AnyValue::from(pairs.get(2).map_or("", |p| p.1).to_owned()));
+
+	record.add_attribute("code.filepath", file.to_owned());
+	record.add_attribute("code.lineno", line as i64);
+	for (k, v) in pairs.iter().skip(3) {
+		record.add_attribute(Key::from(k.to_string()), v.to_string());
+	}
+
+	logger.emit(record);
+}
+
+/// systemd-journald's native protocol, which keeps our key/values as journal fields
+pub struct Journald {
+	#[cfg(unix)]
+	socket: UnixDatagram,
+}
+
+impl Journald {
+	#[cfg(unix)]
+	pub fn connect() -> io::Result<Self> {
+		let socket = UnixDatagram::unbound()?;
+		socket.connect(JOURNALD_SOCKET)?;
+		Ok(Journald { socket })
+	}
+
+	#[cfg(not(unix))]
+	pub fn connect() -> io::Result<Self> {
+		Err(io::Error::new(
+			io::ErrorKind::Unsupported,
+			format!("journald is only supported on unix, not at {JOURNALD_SOCKET}"),
+		))
+	}
+
+	/// the first three pairs must be ts, level, and msg, as journald records its own time and uses PRIORITY for level
+	pub fn send(&self, level: LogLevel, file: &str, line: u32, pairs: &[(&str, &str)]) {
+		let buf = journal_message(level, file, line, pairs);
+
+		#[cfg(unix)]
+		let _ = self.socket.send(&buf);
+	}
+}
+
+fn journal_message(level: LogLevel, file: &str, line: u32, pairs: &[(&str, &str)]) -> Vec<u8> {
+	let mut buf = Vec::new();
+
+	push_journal_field(&mut buf, "MESSAGE", pairs.get(2).map_or("", |p| p.1));
+	push_journal_field(&mut buf, "PRIORITY", &level.severity().to_string());
+	push_journal_field(&mut buf, "SYSLOG_IDENTIFIER", built_info::PKG_NAME);
+	push_journal_field(&mut buf, "CODE_FILE", file);
+	push_journal_field(&mut buf, "CODE_LINE", &line.to_string());
+
+	for (k, v) in pairs.iter().skip(3) {
+		if let Some(name) = journal_field_name(k) {
+			push_journal_field(&mut buf, &name, v);
+		}
+	}
+
+	buf
+}
+
+/// journal field names must be uppercase letters, digits, and underscores, not starting with an underscore or digit
+fn journal_field_name(key: &str) -> Option<String> {
+	let name: String = key
+		.chars()
+		.map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+		.collect();
+	let name = name.trim_start_matches(|c: char| c == '_' || c.is_ascii_digit());
+
+	(!name.is_empty()).then(|| name.to_owned())
+}
+
+fn push_journal_field(buf: &mut Vec<u8>, name: &str, val: &str) {
+	buf.extend_from_slice(name.as_bytes());
+
+	// values with newlines need the binary form: the name, a newline, then the length as a little endian u64
+	if val.contains('\n') {
+		buf.push(b'\n');
+		buf.extend_from_slice(&(val.len() as u64).to_le_bytes());
+	} else {
+		buf.push(b'=');
+	}
+
+	buf.extend_from_slice(val.as_bytes());
+	buf.push(b'\n');
+}
+
+enum SyslogSocket {
+	#[cfg(unix)]
+	Unix(UnixDatagram),
+	Udp(UdpSocket),
+}
+
+/// RFC 5424 syslog, over a unix datagram socket such as /dev/log, or UDP
+pub struct Syslog {
+	socket: SyslogSocket,
+	hostname: String,
+}
+
+impl Syslog {
+	/// address is either udp://host:port, or a path to a unix socket, optionally prefixed with unix://
+	pub fn connect(address: &str) -> io::Result<Self> {
+		let socket = if let Some(host) = address.strip_prefix("udp://") {
+			let socket = UdpSocket::bind(if host.starts_with('[') { "[::]:0" } else { "0.0.0.0:0" })?;
+			socket.connect(host)?;
+			SyslogSocket::Udp(socket)
+		} else {
+			let path = address.strip_prefix("unix://").unwrap_or(address);
+			unix_socket(path)?
+		};
+
+		Ok(Syslog {
+			socket,
+			hostname: hostname().unwrap_or_else(|| "-".to_owned()),
+		})
+	}
+
+	/// the first three pairs must be ts, level, and msg. we use our own timestamp, and the rest are sent as structured
+	/// data
+	pub fn send(&self, level: LogLevel, pairs: &[(&str, &str)]) {
+		// RFC 5424 allows at most microseconds
+		let ts = Utc::now().format("%Y-%m-%dT%H:%M:%S%.6fZ").to_string();
+		let line = syslog_line(&self.hostname, &ts, std::process::id(), level, pairs);
+
+		let _ = match &self.socket {
+			#[cfg(unix)]
+			SyslogSocket::Unix(s) => s.send(line.as_bytes()),
+			SyslogSocket::Udp(s) => s.send(line.as_bytes()),
+		};
+	}
+}
+
+fn syslog_line(hostname: &str, ts: &str, pid: u32, level: LogLevel, pairs: &[(&str, &str)]) -> String {
+	let msg = pairs.get(2).map_or("", |p| p.1);
+
+	let mut sd = String::new();
+	for (k, v) in pairs.iter().skip(3) {
+		sd += &format!(" {}=\"{}\"", sd_name(k), sd_escape(v));
+	}
+	let sd = if sd.is_empty() { "-".to_owned() } else { format!("[{SD_ID}{sd}]") };
+
+	let pri = FACILITY_DAEMON * 8 + level.severity();
+	format!("<{pri}>1 {ts} {hostname} {} {pid} - {sd} {msg}", built_info::PKG_NAME)
+}
+
+#[cfg(unix)]
+fn unix_socket(path: &str) -> io::Result<SyslogSocket> {
+	let socket = UnixDatagram::unbound()?;
+	socket.connect(path)?;
+	Ok(SyslogSocket::Unix(socket))
+}
+
+#[cfg(not(unix))]
+fn unix_socket(path: &str) -> io::Result<SyslogSocket> {
+	Err(io::Error::new(
+		io::ErrorKind::Unsupported,
+		format!("unix sockets are not supported, use udp:// instead of {path}"),
+	))
+}
+
+/// SD-PARAM names are up to 32 printable ascii chars, except =, space, ], and "
+fn sd_name(key: &str) -> String {
+	let name: String = key
+		.chars()
+		.map(|c| if c.is_ascii_graphic() && !matches!(c, '=' | ']' | '"') { c } else { '_' })
+		.take(32)
+		.collect();
+
+	if name.is_empty() { "_".to_owned() } else { name }
+}
+
+fn sd_escape(val: &str) -> String {
+	val.replace('\\', "\\\\").replace('"', "\\\"").replace(']', "\\]")
+}
+
+/// the kernel's hostname on linux, or windows' computer name. other unixes go without, which RFC 5424 allows
+fn hostname() -> Option<String> {
+	std::fs::read_to_string("/proc/sys/kernel/hostname")
+		.or_else(|_| std::env::var("COMPUTERNAME"))
+		.ok()
+		.map(|h| h.trim().to_owned())
+		.filter(|h| !h.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn sanitizes_journal_field_names() {
+		assert_eq!(journal_field_name("container_id").as_deref(), Some("CONTAINER_ID"));
+		assert_eq!(journal_field_name("docker.host").as_deref(), Some("DOCKER_HOST"));
+		// journald reserves leading underscores for trusted fields, and names can't start with a digit
+		assert_eq!(journal_field_name("_pid").as_deref(), Some("PID"));
+		assert_eq!(journal_field_name("2fa_code").as_deref(), Some("FA_CODE"));
+		assert_eq!(journal_field_name("__9").as_deref(), None);
+		assert_eq!(journal_field_name("").as_deref(), None);
+	}
+
+	#[test]
+	fn encodes_journal_fields() {
+		let mut buf = Vec::new();
+		push_journal_field(&mut buf, "A", "one line");
+		push_journal_field(&mut buf, "B", "two\nlines");
+		push_journal_field(&mut buf, "C", "");
+
+		let mut expected = b"A=one line\nB\n".to_vec();
+		expected.extend_from_slice(&9u64.to_le_bytes());
+		expected.extend_from_slice(b"two\nlines\nC=\n");
+		assert_eq!(buf, expected);
+	}
+
+	#[test]
+	fn builds_journal_messages() {
+		let msg = journal_message(
+			LogLevel::Warn,
+			"src/main.rs",
+			42,
+			&[("ts", "now"), ("level", "warn"), ("msg", "hi"), ("container_id", "abc"), ("__", "dropped")],
+		);
+
+		let expected = format!(
+			"MESSAGE=hi\nPRIORITY=4\nSYSLOG_IDENTIFIER={}\nCODE_FILE=src/main.rs\nCODE_LINE=42\nCONTAINER_ID=abc\n",
+			built_info::PKG_NAME
+		);
+		assert_eq!(String::from_utf8(msg).unwrap(), expected);
+	}
+
+	#[test]
+	fn escapes_structured_data() {
+		assert_eq!(sd_name("container_id"), "container_id");
+		assert_eq!(sd_name("a key=\"x]"), "a_key__x_");
+		assert_eq!(sd_name(""), "_");
+		assert_eq!(sd_name(&"k".repeat(40)), "k".repeat(32));
+
+		assert_eq!(sd_escape(r#"a"b]c\d"#), r#"a\"b\]c\\d"#);
+	}
+
+	#[test]
+	fn builds_syslog_lines() {
+		let ts = "2024-05-01T10:00:00.000000Z";
+		let pairs = [("ts", "now"), ("level", "error"), ("msg", "oh no"), ("container_id", "abc"), ("err", "\"x\"")];
+
+		assert_eq!(
+			syslog_line("host", ts, 7, LogLevel::Error, &pairs),
+			format!(r#"<27>1 {ts} host {} 7 - [{SD_ID} container_id="abc" err="\"x\""] oh no"#, built_info::PKG_NAME)
+		);
+		assert_eq!(
+			syslog_line("-", ts, 7, LogLevel::Info, &pairs[..3]),
+			format!("<30>1 {ts} - {} 7 - - oh no", built_info::PKG_NAME)
+		);
+	}
+}
@@ -0,0 +1,522 @@
+// forwards container logs as OTLP log records
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bollard::container::{LogOutput, LogsOptions};
+use bollard::models::ContainerSummary;
+use chrono::DateTime;
+use opentelemetry::logs::{AnyValue, LogRecord, Logger};
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::logs::SdkLogger;
+use tokio::task::JoinHandle;
+use tokio_stream::StreamExt;
+
+use crate::config::{CspyConfig, MetricNaming, Pattern};
+use crate::engine::Connection;
+use crate::s_log::*;
+use crate::{config, labels, self_metrics};
+
+/// a container label to opt a container in or out of log forwarding, regardless of the include and exclude lists
+const LOGS_LABEL: &str = "containerspy.logs";
+
+/// how long to wait for more lines of a multiline record before sending it anyway
+const MULTILINE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// so that a container that never logs a start line doesn't build one enormous record
+const MULTILINE_MAX_LINES: usize = 500;
+
+/// positions older than this are dropped, as we can't tell a stopped container from a removed one
+const POSITION_MAX_AGE: i64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+fn now_ns() -> i64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as i64
+}
+
+/// unix time in ns of the last forwarded log line of each container, so workers can resume where they left off
+static POSITIONS: Mutex<BTreeMap<String, i64>> = Mutex::new(BTreeMap::new());
+static POSITIONS_DIRTY: AtomicBool = AtomicBool::new(false);
+
+static STARTED_AT: LazyLock<i64> = LazyLock::new(now_ns);
+
+/// loads saved positions from the state file, if there is one
+pub fn load_positions(path: &str) {
+	LazyLock::force(&STARTED_AT);
+
+	let saved = match std::fs::read_to_string(path) {
+		Ok(s) => s,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return,
+		Err(e) => {
+			warn(format_args!("Failed to read container log state file {path}: {e}"), [("path", path)]);
+			return;
+		}
+	};
+
+	match serde_json::from_str::<BTreeMap<String, i64>>(&saved) {
+		Ok(positions) => POSITIONS.lock().unwrap().extend(positions),
+		Err(e) => warn(format_args!("Invalid container log state file {path}, ignoring it: {e}"), [("path", path)]),
+	}
+}
+
+/// writes the positions to the state file, if they changed since the last save
+pub fn save_positions(path: &str) {
+	if !POSITIONS_DIRTY.swap(false, Ordering::Relaxed) {
+		return;
+	}
+
+	let json = {
+		let mut positions = POSITIONS.lock().unwrap();
+		let cutoff = now_ns() - POSITION_MAX_AGE;
+		positions.retain(|_, ts| *ts > cutoff);
+
+		serde_json::to_string(&*positions).unwrap()
+	};
+
+	// write then rename, so a crash mid-write doesn't lose every position
+	let tmp = format!("{path}.tmp");
+	if let Err(e) = std::fs::write(&tmp, json).and_then(|_| std::fs::rename(&tmp, path)) {
+		POSITIONS_DIRTY.store(true, Ordering::Relaxed);
+		warn(format_args!("Failed to write container log state file {path}: {e}"), [("path", path)]);
+	}
+}
+
+/// saves the positions every few seconds while a state file is configured
+pub fn spawn_position_saver() -> JoinHandle<()> {
+	tokio::spawn(async {
+		loop {
+			tokio::time::sleep(Duration::from_secs(5)).await;
+
+			if let Some(path) = &config::current().container_logs_state_file {
+				save_positions(path);
+			}
+		}
+	})
+}
+
+fn set_position(container_id: &str, ts: i64) {
+	POSITIONS.lock().unwrap().insert(container_id.to_owned(), ts);
+	POSITIONS_DIRTY.store(true, Ordering::Relaxed);
+}
+
+/// where to start reading a container's logs from: where we left off, or all of them for containers created since
+/// we started, or else from now, so we don't flood the pipeline with the whole history of every container
+fn start_position(container: &ContainerSummary) -> i64 {
+	if let Some(pos) = container.id.as_ref().and_then(|id| POSITIONS.lock().unwrap().get(id).copied()) {
+		return pos;
+	}
+
+	match container.created {
+		Some(created) if created * 1_000_000_000 >= *STARTED_AT => 0,
+		_ => now_ns(),
+	}
+}
+
+/// if logs should be forwarded for this container
+pub fn should_forward(container: &ContainerSummary, config: &CspyConfig) -> bool {
+	match container.labels.as_ref().and_then(|l| l.get(LOGS_LABEL)).map(String::as_str) {
+		Some("true") => return true,
+		Some("false") => return false,
+		_ => {}
+	}
+
+	let name = labels::container_name(container).unwrap_or_default();
+
+	if config.container_logs_exclude.iter().any(|p| p.is_match(&name)) {
+		return false;
+	}
+
+	config.container_logs_include.is_empty() || config.container_logs_include.iter().any(|p| p.is_match(&name))
+}
+
+/// a token bucket, allowing bursts of up to a second's worth of records
+struct RateLimiter {
+	rate: f64,
+	tokens: f64,
+	last: Instant,
+}
+
+impl RateLimiter {
+	fn new(rate: u64, now: Instant) -> Self {
+		RateLimiter {
+			rate: rate as f64,
+			tokens: rate as f64,
+			last: now,
+		}
+	}
+
+	fn allow(&mut self, now: Instant) -> bool {
+		self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.rate);
+		self.last = now;
+
+		if self.tokens >= 1.0 {
+			self.tokens -= 1.0;
+			true
+		} else {
+			false
+		}
+	}
+}
+
+/// a log record that may still have lines joined onto it
+struct Record {
+	ts: i64,
+	/// timestamp of the last line, which is how far we've read once this is sent
+	last_ts: i64,
+	body: String,
+	lines: usize,
+}
+
+/// one of a container's output streams, buffering partial lines and multiline records
+struct Stream {
+	name: &'static str,
+	partial: Vec<u8>,
+	pending: Option<Record>,
+}
+
+impl Stream {
+	fn new(name: &'static str) -> Self {
+		Stream {
+			name,
+			partial: Vec::new(),
+			pending: None,
+		}
+	}
+}
+
+struct Forwarder {
+	container_id: String,
+	logger: Arc<SdkLogger>,
+	attrs: Vec<KeyValue>,
+	multiline: Option<Pattern>,
+	limiter: Option<RateLimiter>,
+	/// lines at or before this were already forwarded by a previous worker
+	skip_until: i64,
+}
+
+impl Forwarder {
+	fn push(&mut self, stream: &mut Stream, message: &[u8]) {
+		stream.partial.extend_from_slice(message);
+
+		while let Some(end) = stream.partial.iter().position(|b| *b == b'\n') {
+			let line: Vec<u8> = stream.partial.drain(..=end).collect();
+			let line = String::from_utf8_lossy(&line[..end]);
+			let line = line.strip_suffix('\r').unwrap_or(&line);
+
+			self.push_line(stream, line);
+		}
+	}
+
+	fn push_line(&mut self, stream: &mut Stream, line: &str) {
+		// with timestamps on, docker prefixes each line with its RFC 3339 timestamp and a space
+		let (ts, text) = match line.split_once(' ') {
+			Some((ts, text)) => match DateTime::parse_from_rfc3339(ts).ok().and_then(|t| t.timestamp_nanos_opt()) {
+				Some(ts) => (ts, text),
+				None => (now_ns(), line),
+			},
+			None => (now_ns(), line),
+		};
+
+		if ts <= self.skip_until {
+			return;
+		}
+
+		let continues = match (&self.multiline, &stream.pending) {
+			(Some(start), Some(rec)) => !start.is_match(text) && rec.lines < MULTILINE_MAX_LINES,
+			_ => false,
+		};
+
+		if continues {
+			let rec = stream.pending.as_mut().unwrap();
+			rec.body.push('\n');
+			rec.body.push_str(text);
+			rec.last_ts = ts;
+			rec.lines += 1;
+			return;
+		}
+
+		self.flush(stream);
+		stream.pending = Some(Record {
+			ts,
+			last_ts: ts,
+			body: text.to_owned(),
+			lines: 1,
+		});
+
+		// without multiline, there's nothing to wait for
+		if self.multiline.is_none() {
+			self.flush(stream);
+		}
+	}
+
+	/// sends whatever is left when the stream ends, including a last line with no newline
+	fn finish(&mut self, stream: &mut Stream) {
+		if !stream.partial.is_empty() {
+			let line = String::from_utf8_lossy(&std::mem::take(&mut stream.partial)).into_owned();
+			self.push_line(stream, &line);
+		}
+
+		self.flush(stream);
+	}
+
+	fn flush(&mut self, stream: &mut Stream) {
+		let Some(rec) = stream.pending.take() else {
+			return;
+		};
+
+		// dropped records still count as read, so we don't retry them after a restart
+		set_position(&self.container_id, rec.last_ts);
+
+		if let Some(limiter) = &mut self.limiter {
+			if !limiter.allow(Instant::now()) {
+				self_metrics::container_log_record(&self.container_id, "dropped");
+				return;
+			}
+		}
+
+		let mut record = self.logger.create_log_record();
+		record.set_timestamp(UNIX_EPOCH + Duration::from_nanos(rec.ts.max(0) as u64));
+		record.set_observed_timestamp(SystemTime::now());
+		record.set_body(AnyValue::from(rec.body));
+		record.add_attributes(self.attrs.iter().map(|kv| (kv.key.clone(), kv.value.to_string())));
+		record.add_attribute("log.iostream", stream.name);
+
+		self.logger.emit(record);
+		self_metrics::container_log_record(&self.container_id, "forwarded");
+	}
+}
+
+pub fn launch_logs_task(
+	container: ContainerSummary,
+	conn: Arc<Connection>,
+	logger: Arc<SdkLogger>,
+	inspection: labels::SharedInspect,
+) -> JoinHandle<()> {
+	tokio::spawn(async move {
+		let container_id = container.id.clone().unwrap();
+
+		let config = config::current();
+
+		// only needed for env labels, and shared with the stats worker, which inspects the container anyway
+		let details = if config.env_labels.is_empty() {
+			None
+		} else {
+			labels::inspect_once(&inspection, &conn.docker, &container_id).await
+		};
+
+		// the same attributes as the container's metrics, in whichever naming scheme the metrics use
+		let labels = labels::container_attributes(&container, details, &conn, &config);
+		let attrs = if config.metric_naming == MetricNaming::Semconv { labels.semconv } else { labels.cadvisor };
+
+		let skip_until = start_position(&container);
+
+		let mut fwd = Forwarder {
+			container_id: container_id.clone(),
+			logger,
+			attrs,
+			multiline: config.container_logs_multiline.clone(),
+			limiter: config.container_logs_rate_limit.map(|rate| RateLimiter::new(rate, Instant::now())),
+			skip_until,
+		};
+
+		// don't keep an old config alive for the life of the worker
+		drop(config);
+
+		let mut logs = conn.docker.logs(
+			&container_id,
+			Some(LogsOptions::<String> {
+				follow: true,
+				stdout: true,
+				stderr: true,
+				// since is in whole seconds, the rest of the second is skipped by skip_until
+				since: skip_until / 1_000_000_000,
+				timestamps: true,
+				..Default::default()
+			}),
+		);
+
+		let mut stdout = Stream::new("stdout");
+		let mut stderr = Stream::new("stderr");
+
+		loop {
+			let next = match tokio::time::timeout(MULTILINE_TIMEOUT, logs.next()).await {
+				Ok(next) => next,
+				// quiet for a bit, so send anything waiting for more lines
+				Err(_) => {
+					fwd.flush(&mut stdout);
+					fwd.flush(&mut stderr);
+					continue;
+				}
+			};
+
+			match next {
+				None => break,
+				Some(Ok(LogOutput::StdOut { message } | LogOutput::Console { message })) => fwd.push(&mut stdout, &message),
+				Some(Ok(LogOutput::StdErr { message })) => fwd.push(&mut stderr, &message),
+				Some(Ok(LogOutput::StdIn { .. })) => {}
+				Some(Err(err)) => {
+					error(
+						format_args!("Failed to read logs for container {container_id}: {err:?}"),
+						[("container_id", &*container_id)],
+					);
+					fwd.finish(&mut stdout);
+					fwd.finish(&mut stderr);
+
+					// errors here are usually permanent, like a logging driver docker can't read from, so rather than
+					// ending and being relaunched on the next tick, wait to be stopped when the container goes away
+					std::future::pending::<()>().await;
+				}
+			}
+		}
+
+		fwd.finish(&mut stdout);
+		fwd.finish(&mut stderr);
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use opentelemetry::logs::LoggerProvider;
+	use opentelemetry::InstrumentationScope;
+	use opentelemetry_sdk::error::OTelSdkResult;
+	use opentelemetry_sdk::logs::{LogProcessor, SdkLogRecord, SdkLoggerProvider};
+
+	/// a record's body, timestamp, and stream
+	type Emitted = (String, Option<SystemTime>, String);
+
+	/// keeps every record emitted
+	#[derive(Debug, Clone, Default)]
+	struct Capture(Arc<Mutex<Vec<Emitted>>>);
+
+	impl LogProcessor for Capture {
+		fn emit(&self, rec: &mut SdkLogRecord, _scope: &InstrumentationScope) {
+			let text = |v: Option<&AnyValue>| match v {
+				Some(AnyValue::String(s)) => s.to_string(),
+				other => format!("{other:?}"),
+			};
+			let stream = rec.attributes_iter().find(|(k, _)| k.as_str() == "log.iostream").map(|(_, v)| v);
+
+			self.0.lock().unwrap().push((text(rec.body()), rec.timestamp(), text(stream)));
+		}
+
+		fn force_flush(&self) -> OTelSdkResult {
+			Ok(())
+		}
+
+		fn shutdown(&self) -> OTelSdkResult {
+			Ok(())
+		}
+	}
+
+	fn forwarder(multiline: Option<&str>) -> (Forwarder, Capture) {
+		let capture = Capture::default();
+		let provider = SdkLoggerProvider::builder().with_log_processor(capture.clone()).build();
+
+		let fwd = Forwarder {
+			container_id: "test".to_owned(),
+			logger: Arc::new(provider.logger("test")),
+			attrs: Vec::new(),
+			multiline: multiline.map(|p| p.parse().unwrap()),
+			limiter: None,
+			skip_until: 0,
+		};
+		(fwd, capture)
+	}
+
+	fn bodies(capture: &Capture) -> Vec<String> {
+		capture.0.lock().unwrap().iter().map(|(body, _, _)| body.clone()).collect()
+	}
+
+	#[test]
+	fn parses_timestamps() {
+		let (mut fwd, capture) = forwarder(None);
+		let mut stdout = Stream::new("stdout");
+
+		fwd.push(&mut stdout, b"2024-05-01T10:00:00.123456789Z hello world\r\n2024-05-01T10:00:01Z");
+		// lines can be split across messages, and the last one may not end in a newline
+		fwd.push(&mut stdout, b" second\nno timestamp\n2024-05-01T10:00:02Z last");
+		fwd.finish(&mut stdout);
+
+		let records = capture.0.lock().unwrap().clone();
+		let bodies: Vec<_> = records.iter().map(|(body, _, _)| body.as_str()).collect();
+		assert_eq!(bodies, ["hello world", "second", "no timestamp", "last"]);
+
+		assert_eq!(records[0].1, Some(UNIX_EPOCH + Duration::new(1_714_557_600, 123_456_789)));
+		assert_eq!(records[1].1, Some(UNIX_EPOCH + Duration::from_secs(1_714_557_601)));
+		assert_eq!(records[0].2, "stdout");
+	}
+
+	#[test]
+	fn skips_lines_already_forwarded() {
+		let (mut fwd, capture) = forwarder(None);
+		fwd.skip_until = DateTime::parse_from_rfc3339("2024-05-01T10:00:01Z").unwrap().timestamp_nanos_opt().unwrap();
+		let mut stdout = Stream::new("stdout");
+
+		fwd.push(&mut stdout, b"2024-05-01T10:00:00Z a\n2024-05-01T10:00:01Z b\n2024-05-01T10:00:01.5Z c\n");
+
+		assert_eq!(bodies(&capture), ["c"]);
+	}
+
+	#[test]
+	fn joins_multiline_records() {
+		// a record starts with a line that isn't indented
+		let (mut fwd, capture) = forwarder(Some(r"\S.*"));
+		let mut stdout = Stream::new("stdout");
+		let mut stderr = Stream::new("stderr");
+
+		fwd.push(&mut stderr, b"2024-05-01T10:00:00Z Exception: boom\n2024-05-01T10:00:00Z   at a()\n");
+		fwd.push(&mut stdout, b"2024-05-01T10:00:00Z unrelated\n");
+		fwd.push(&mut stderr, b"2024-05-01T10:00:01Z   at b()\n2024-05-01T10:00:02Z next\n");
+
+		// the last record waits for more lines until it's flushed
+		assert_eq!(bodies(&capture), ["Exception: boom\n  at a()\n  at b()"]);
+
+		fwd.finish(&mut stdout);
+		fwd.finish(&mut stderr);
+
+		let records = capture.0.lock().unwrap().clone();
+		assert_eq!(records.len(), 3);
+		// a joined record has its first line's timestamp
+		assert_eq!(records[0].1, Some(UNIX_EPOCH + Duration::from_secs(1_714_557_600)));
+		assert_eq!(records[0].2, "stderr");
+		assert_eq!((records[1].0.as_str(), records[1].2.as_str()), ("unrelated", "stdout"));
+		assert_eq!((records[2].0.as_str(), records[2].2.as_str()), ("next", "stderr"));
+	}
+
+	#[test]
+	fn splits_long_multiline_records() {
+		let (mut fwd, capture) = forwarder(Some(r"\S.*"));
+		let mut stdout = Stream::new("stdout");
+
+		fwd.push(&mut stdout, b"2024-05-01T10:00:00Z start\n");
+		for _ in 0..MULTILINE_MAX_LINES + 9 {
+			fwd.push(&mut stdout, b"2024-05-01T10:00:00Z   more\n");
+		}
+		fwd.finish(&mut stdout);
+
+		let lines: Vec<_> = bodies(&capture).iter().map(|b| b.lines().count()).collect();
+		assert_eq!(lines, [MULTILINE_MAX_LINES, 10]);
+	}
+
+	#[test]
+	fn rate_limits() {
+		let start = Instant::now();
+		let at = |ms| start + Duration::from_millis(ms);
+		let mut limiter = RateLimiter::new(2, start);
+
+		// a burst of up to a second's worth, then nothing until the bucket refills
+		assert!(limiter.allow(at(0)));
+		assert!(limiter.allow(at(0)));
+		assert!(!limiter.allow(at(0)));
+		assert!(!limiter.allow(at(250)));
+		assert!(limiter.allow(at(500)));
+		assert!(!limiter.allow(at(500)));
+
+		// the bucket never holds more than a second's worth
+		assert!(limiter.allow(at(10_000)));
+		assert!(limiter.allow(at(10_000)));
+		assert!(!limiter.allow(at(10_000)));
+	}
+}
@@ -1,32 +1,47 @@
 use anyhow::Result;
-use bollard::Docker;
-use config::CONFIG;
-use opentelemetry_otlp::{MetricExporter, Protocol, WithExportConfig};
+use opentelemetry_otlp::{LogExporter, MetricExporter, Protocol, WithExportConfig};
+use opentelemetry_sdk::logs::SdkLoggerProvider;
 use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
-use std::env::args;
 use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use opentelemetry::logs::LoggerProvider;
 use opentelemetry::metrics::MeterProvider;
-use tokio::task::JoinHandle;
-use tokio::time::MissedTickBehavior;
+use tokio::sync::{watch, Notify};
 use tokio_util::sync::CancellationToken;
+use crate::config::CspyConfig;
 use crate::s_log::*;
+use crate::watcher::{Shared, Watcher};
 
+mod cli;
+mod compose;
 mod config;
+mod docker_host;
+mod engine;
+mod events_task;
+mod health;
+mod labels;
+mod log_sinks;
+mod logs_task;
+mod metrics;
+mod networks;
+mod self_metrics;
 mod stats_task;
 mod s_log;
+mod swarm;
+mod totals;
+mod watcher;
 
 // includes data from Cargo.toml and other sources using the `built` crate
 pub mod built_info {
 	include!(concat!(env!("OUT_DIR"), "/built.rs"));
 }
 
-fn setup_otlp() -> Result<SdkMeterProvider> {
-	let metric_exporter = match CONFIG.otlp_protocol {
+fn setup_otlp(config: &CspyConfig) -> Result<SdkMeterProvider> {
+	let metric_exporter = match config.otlp_protocol {
 		Protocol::HttpBinary | Protocol::HttpJson => {
 			let builder = MetricExporter::builder()
 				.with_http()
-				.with_protocol(CONFIG.otlp_protocol);
-			let builder = if let Some(e) = &CONFIG.otlp_endpoint {
+				.with_protocol(config.otlp_protocol);
+			let builder = if let Some(e) = &config.otlp_endpoint {
 				builder.with_endpoint(e)
 			} else {
 				builder
@@ -39,7 +54,7 @@ fn setup_otlp() -> Result<SdkMeterProvider> {
 				.with_tonic()
 				.with_protocol(Protocol::Grpc);
 
-			let builder = if let Some(e) = &CONFIG.otlp_endpoint {
+			let builder = if let Some(e) = &config.otlp_endpoint {
 				builder.with_endpoint(e.as_str())
 			} else {
 				builder
@@ -53,8 +68,8 @@ fn setup_otlp() -> Result<SdkMeterProvider> {
 	// else use default behaviour which reads OTEL_METRIC_EXPORT_INTERVAL else uses one minute as the interval
 	// note that a PeriodicReader without setting .with_interval is equivalent to using .with_periodic_exporter
 
-	let reader_builder = PeriodicReader::builder(metric_exporter);
-	let reader_builder = if let Some(interval) = CONFIG.otlp_export_interval {
+	let reader_builder = PeriodicReader::builder(self_metrics::TimedExporter(metric_exporter));
+	let reader_builder = if let Some(interval) = config.otlp_export_interval {
 		reader_builder.with_interval(Duration::from_millis(interval))
 	} else {
 		reader_builder
@@ -65,40 +80,261 @@ fn setup_otlp() -> Result<SdkMeterProvider> {
 		.build())
 }
 
+/// sets up sending our own logs, container logs, or docker events via OTLP, if any are enabled
+fn setup_otlp_logs(config: &CspyConfig) -> Result<Option<SdkLoggerProvider>> {
+	if !config.otlp_logs && !config.container_logs && !config.container_events {
+		return Ok(None);
+	}
+
+	let endpoint = config.logs_endpoint();
+
+	let log_exporter = match config.otlp_protocol {
+		Protocol::HttpBinary | Protocol::HttpJson => {
+			let builder = LogExporter::builder()
+				.with_http()
+				.with_protocol(config.otlp_protocol);
+			let builder = if let Some(e) = &endpoint {
+				builder.with_endpoint(e)
+			} else {
+				builder
+			};
+
+			builder.build()?
+		}
+		Protocol::Grpc => {
+			let builder = LogExporter::builder()
+				.with_tonic()
+				.with_protocol(Protocol::Grpc);
+
+			let builder = if let Some(e) = &endpoint {
+				builder.with_endpoint(e.as_str())
+			} else {
+				builder
+			};
+
+			builder.build()?
+		}
+	};
+
+	Ok(Some(SdkLoggerProvider::builder()
+		.with_batch_exporter(log_exporter)
+		.build()))
+}
+
+/// swaps in a new OTLP logs provider, flushing and shutting down the old one
+fn replace_logger_provider(
+	old: Option<SdkLoggerProvider>,
+	new: Option<SdkLoggerProvider>,
+	own_logs: bool,
+) -> Option<SdkLoggerProvider> {
+	log_sinks::set_otlp_logger(new.as_ref().filter(|_| own_logs).map(|p| p.logger("containerspy")));
+
+	if let Some(old) = old {
+		let _ = old.force_flush();
+		let _ = old.shutdown();
+	}
+
+	new
+}
+
+/// how long to wait before retrying engines that couldn't be connected to
+const CONNECT_RETRY: Duration = Duration::from_secs(10);
+
+/// how often to list containers, twice per export so new containers show up in the next export
+fn search_interval(config: &CspyConfig) -> Duration {
+	Duration::from_millis(config.otlp_export_interval.unwrap_or(6000)) / 2
+}
+
+/// starts or stops watchers so there is one for each engine in the config. a watcher whose address changed is only
+/// replaced if the new address is valid
+async fn update_watchers(
+	watchers: &mut BTreeMap<Option<String>, Watcher>,
+	config: &CspyConfig,
+	shared: &watch::Sender<Shared>,
+) {
+	let engines = config.engines();
+
+	let removed: Vec<_> = watchers.keys().filter(|host| !engines.iter().any(|(h, _)| h == *host)).cloned().collect();
+	for host in removed {
+		info("No longer watching docker host", [("docker_host", host.as_deref().unwrap_or_default())]);
+		watchers.remove(&host).unwrap().shutdown().await;
+	}
+	health::retain_hosts(&engines.iter().map(|(_, a)| a.as_deref().unwrap_or("default")).collect::<Vec<_>>());
+
+	for (host, address) in engines {
+		if watchers.get(&host).is_some_and(|w| w.address == address && w.timeout == config.docker_timeout) {
+			continue;
+		}
+
+		let connected = match &host {
+			Some(entry) => docker_host::connect_host(entry, config.docker_timeout).await,
+			None => docker_host::connect(address.as_deref(), config.docker_timeout).await,
+		};
+		let client = match connected {
+			Ok(c) => c,
+			Err(e) => {
+				// not ready until it can be connected to, but still alive as it's retried
+				let name = address.as_deref().unwrap_or("default");
+				health::docker_reachable(name, false);
+				health::tick(name, CONNECT_RETRY);
+				error(
+					format_args!("Failed to connect to docker host, retrying in {}s: {e}", CONNECT_RETRY.as_secs()),
+					[("docker_host", address.as_deref().unwrap_or_default())],
+				);
+				continue;
+			}
+		};
+
+		if let Some(old) = watchers.remove(&host) {
+			info("Docker socket or timeout changed, reconnecting", []);
+			old.shutdown().await;
+		}
+
+		watchers.insert(host.clone(), Watcher::spawn(client, address, host, shared.subscribe()));
+	}
+}
+
+/// notifies whenever the config should be reloaded: on SIGHUP, or when the config file changes if watching is enabled
+fn spawn_reload_watcher(config_path: String) -> Arc<Notify> {
+	let notify = Arc::new(Notify::new());
+
+	#[cfg(unix)]
+	{
+		let notify = notify.clone();
+		tokio::spawn(async move {
+			use tokio::signal::unix::{signal, SignalKind};
+
+			let Ok(mut sighup) = signal(SignalKind::hangup()) else {
+				warn("Failed to setup SIGHUP handler, config will not be reloaded on SIGHUP", []);
+				return;
+			};
+
+			while sighup.recv().await.is_some() {
+				info("Received SIGHUP, reloading config", []);
+				notify.notify_one();
+			}
+		});
+	}
+
+	let n2 = notify.clone();
+	tokio::spawn(async move {
+		let mut last_mtime = config::config_mtime(&config_path);
+
+		loop {
+			// re-read every time, as a reload may enable, disable, or change the watch interval
+			let Some(interval) = config::current().config_watch_interval else {
+				tokio::time::sleep(Duration::from_secs(5)).await;
+				continue;
+			};
+
+			tokio::time::sleep(Duration::from_millis(interval)).await;
+
+			let mtime = config::config_mtime(&config_path);
+			if mtime != last_mtime {
+				last_mtime = mtime;
+				info("Config file changed, reloading config", [("path", &*config_path)]);
+				n2.notify_one();
+			}
+		}
+	});
+
+	notify
+}
+
+fn load_config_or_exit(cli: &cli::Cli) -> CspyConfig {
+	match config::load(cli) {
+		Ok(cfg) => cfg,
+		Err(e) => {
+			fatal(format_args!("Failed to load config: {e}"), e.rich());
+			std::process::exit(config::EXIT_CONFIG);
+		}
+	}
+}
+
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<()> {
 	// handle CLI stuff
-	for arg in args() {
-		if ["--version", "--help"].contains(&arg.as_str()) {
+	let cli = cli::parse(std::env::args())?;
+
+	match cli.action {
+		cli::Action::Help | cli::Action::Version => {
 			println!(
 				"ContainerSpy v{}, made with love by {}",
 				built_info::PKG_VERSION,
 				built_info::PKG_AUTHORS.replace(":", ", ")
 			);
 
-			if arg == "--help" {
-				println!(
-					"\n{}",
-					include_str!("help.txt")
-						.trim_end()
-						.replace("{{REPO_URL}}", built_info::PKG_REPOSITORY)
-				);
+			if let cli::Action::Help = cli.action {
+				println!("\n{}", cli::help_text());
+			}
+
+			return Ok(());
+		}
+		cli::Action::CheckConfig => {
+			load_config_or_exit(&cli);
+			println!("Config at {} is valid", config::config_path(&cli));
+			return Ok(());
+		}
+		cli::Action::Healthcheck => {
+			let Some(addr) = load_config_or_exit(&cli).http_listen else {
+				eprintln!("http_listen is not set, so there is no server to check");
+				std::process::exit(1);
+			};
+
+			match health::healthcheck(addr) {
+				Ok(()) => println!("healthy"),
+				Err(e) => {
+					eprintln!("{e}");
+					std::process::exit(1);
+				}
 			}
 
 			return Ok(());
 		}
+		cli::Action::PrintConfig => {
+			let cfg = load_config_or_exit(&cli);
+			let sources = config::sources(&cli)?;
+
+			let entries = cfg.entries();
+			let source = |key| sources.get(key).map(String::as_str).unwrap_or("unknown");
+
+			// wide enough for the longest key and source, plus a gap
+			let key_w = entries.iter().map(|(key, _)| key.len()).max().unwrap_or_default() + 2;
+			let source_w = entries.iter().map(|(key, _)| source(key).len()).max().unwrap_or_default() + 2;
+
+			for (key, value) in &entries {
+				println!("{key:<key_w$}{:<source_w$}{value}", source(key));
+			}
+
+			return Ok(());
+		}
+		cli::Action::Run => {}
 	}
 
-	// open a docker connection
-	let docker = Arc::new(if let Some(path) = &CONFIG.docker_socket {
-		Docker::connect_with_socket(path, 60, bollard::API_DEFAULT_VERSION)?
-	} else {
-		Docker::connect_with_local_defaults()?
-	});
+	config::init(load_config_or_exit(&cli));
+
+	// send logs via OTLP as early as possible, so startup errors make it there too
+	let mut logger_provider = match setup_otlp_logs(&config::current()) {
+		Ok(p) => replace_logger_provider(None, p, config::current().otlp_logs),
+		Err(e) => {
+			error(format_args!("Failed to setup OTLP log exporter, logs will not be sent via OTLP: {e}"), []);
+			None
+		}
+	};
 
 	// connect the OTLP exporter
-	let meter_provider = Arc::new(setup_otlp()?);
-	let meter = Arc::new(meter_provider.meter("cspy_worker"));
+	let mut meter_provider = Arc::new(setup_otlp(&config::current())?);
+	self_metrics::init(&meter_provider.meter("cspy_self"));
+
+	// what the watchers need from here, updated on reload
+	let (shared, _) = watch::channel(Shared {
+		meter: Arc::new(meter_provider.meter("cspy_worker")),
+		logger_provider: logger_provider.clone(),
+		interval: search_interval(&config::current()),
+		workers_gen: 0,
+		log_workers_gen: 0,
+		events_gen: 0,
+	});
 
 	// fetch-report loop with graceful shutdown
 	let shutdown_token = CancellationToken::new();
@@ -112,63 +348,116 @@ async fn main() -> Result<()> {
 		}
 	});
 
-	let mut container_search_interval =
-		tokio::time::interval(Duration::from_millis(CONFIG.otlp_export_interval.unwrap_or(6000)) / 2);
-	container_search_interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+	let reload_notify = spawn_reload_watcher(config::config_path(&cli));
+
+	let mut http_server = config::current().http_listen.map(health::spawn_server);
+
+	if let Some(path) = &config::current().container_logs_state_file {
+		logs_task::load_positions(path);
+	}
+	logs_task::spawn_position_saver();
+
+	// a discovery loop for each docker engine, each with its own workers. engines that can't be connected to yet don't
+	// stop the others from being watched, and are retried
+	let mut watchers: BTreeMap<Option<String>, Watcher> = BTreeMap::new();
+	update_watchers(&mut watchers, &config::current(), &shared).await;
 
-	let mut tasks: BTreeMap<String, JoinHandle<()>> = BTreeMap::new();
+	let mut connect_retry = tokio::time::interval_at(tokio::time::Instant::now() + CONNECT_RETRY, CONNECT_RETRY);
 
 	loop {
+		let unwatched = config::current().engines().iter().any(|(host, _)| !watchers.contains_key(host));
+
 		tokio::select! {
-			_ = container_search_interval.tick() => {}
-			_ = shutdown_token.cancelled() => { break }
-		}
+			_ = connect_retry.tick(), if unwatched => {
+				update_watchers(&mut watchers, &config::current(), &shared).await;
+			}
+			_ = reload_notify.notified() => {
+				let old_cfg = match config::reload(&cli) {
+					Ok(old) => old,
+					Err(e) => {
+						error(format_args!("Failed to reload config, keeping the old config: {e}"), e.rich());
+						continue;
+					}
+				};
+				let new_cfg = config::current();
 
-		let containers = docker.list_containers::<String>(None).await?;
-		let mut containers: Vec<_> = containers.into_iter().filter(|c| c.id.is_some()).collect();
+				// workers hold onto the meter and label settings, so they need restarting if any change
+				let mut restart_workers = old_cfg.workers_changed(&new_cfg);
+				let mut new_meter = None;
 
-		containers.sort_by(|a, b| a.id.as_ref().unwrap().cmp(b.id.as_ref().unwrap()));
+				if old_cfg.exporter_changed(&new_cfg) {
+					match setup_otlp(&new_cfg) {
+						Ok(new_provider) => {
+							info("OTLP exporter settings changed, rebuilding exporter", []);
+							let _ = meter_provider.force_flush();
+							let _ = meter_provider.shutdown();
 
-		let mut to_remove = Vec::new();
+							meter_provider = Arc::new(new_provider);
+							new_meter = Some(Arc::new(meter_provider.meter("cspy_worker")));
+							self_metrics::init(&meter_provider.meter("cspy_self"));
+							restart_workers = true;
+						}
+						Err(e) => error(format_args!("Failed to rebuild OTLP exporter, keeping the old one: {e}"), []),
+					}
+				}
 
-		for (cont, handle) in &tasks {
-			// funny O(n^2) loop
-			if containers
-				.binary_search_by(|c| c.id.as_ref().unwrap().cmp(cont))
-				.is_err()
-			{
-				debug(format_args!("Killing worker for {}", cont), [("container_id", &**cont)]);
-				handle.abort();
-				to_remove.push(cont.clone());
-			}
-		}
+				let mut logs_rebuilt = false;
+				if old_cfg.logs_exporter_changed(&new_cfg) {
+					match setup_otlp_logs(&new_cfg) {
+						Ok(new_provider) => {
+							info("OTLP log settings changed, rebuilding log exporter", []);
+							logger_provider = replace_logger_provider(logger_provider.take(), new_provider, new_cfg.otlp_logs);
+							logs_rebuilt = true;
+						}
+						Err(e) => error(format_args!("Failed to rebuild OTLP log exporter, keeping the old one: {e}"), []),
+					}
+				}
 
-		for cont in to_remove.into_iter() {
-			tasks.remove(&cont);
-		}
+				if old_cfg.http_listen != new_cfg.http_listen {
+					if let Some(server) = http_server.take() {
+						server.abort();
+					}
+					http_server = new_cfg.http_listen.map(health::spawn_server);
+				}
 
-		// now, add any new ones
-		for cont in containers {
-			let id_string = cont.id.as_ref().unwrap();
-			if !tasks.contains_key(id_string) {
-				debug(format_args!("Launching worker for {}", id_string), [("container_id", &**id_string)]);
-				// all this string cloning hurts me
-				tasks.insert(
-					id_string.clone(),
-					stats_task::launch_stats_task(cont, docker.clone(), meter.clone()),
-				);
+				// each watcher restarts its own workers as needed
+				shared.send_modify(|s| {
+					if let Some(meter) = new_meter {
+						s.meter = meter;
+					}
+					s.logger_provider = logger_provider.clone();
+					s.interval = search_interval(&new_cfg);
+
+					if restart_workers {
+						s.workers_gen += 1;
+					}
+					if logs_rebuilt || old_cfg.log_workers_changed(&new_cfg) {
+						s.log_workers_gen += 1;
+					}
+					if logs_rebuilt || old_cfg.container_events != new_cfg.container_events {
+						s.events_gen += 1;
+					}
+				});
+
+				update_watchers(&mut watchers, &new_cfg, &shared).await;
 			}
+			_ = shutdown_token.cancelled() => { break }
 		}
 	}
 
-	// abort all stats tasks
-	for task in tasks.into_values() {
-		task.abort();
+	// stop all watchers, which abort their stats, log, and event tasks
+	for watcher in watchers.into_values() {
+		watcher.shutdown().await;
 	}
 
 	debug("Exiting cleanly", []);
 
 	let _ = meter_provider.force_flush();
+	if let Some(path) = &config::current().container_logs_state_file {
+		logs_task::save_positions(path);
+	}
+
+	replace_logger_provider(logger_provider, None, false);
 
 	Ok(())
 }
@@ -0,0 +1,351 @@
+// metrics that can be emitted under cAdvisor names, OpenTelemetry semantic convention names, or both
+
+use opentelemetry::metrics::{Counter, Gauge, Meter};
+use opentelemetry::{KeyValue, Value};
+
+use crate::config::MetricNaming;
+
+/// attributes for each naming scheme
+#[derive(Clone, Default)]
+pub struct Labels {
+	pub cadvisor: Vec<KeyValue>,
+	pub semconv: Vec<KeyValue>,
+}
+
+impl Labels {
+	/// adds an attribute that has a different key in each naming scheme
+	pub fn push(&mut self, cadvisor_key: &'static str, semconv_key: &'static str, value: impl Into<Value>) {
+		let value = value.into();
+		self.cadvisor.push(KeyValue::new(cadvisor_key, value.clone()));
+		self.semconv.push(KeyValue::new(semconv_key, value));
+	}
+
+	/// clones these labels with an extra attribute
+	pub fn with(&self, cadvisor_key: &'static str, semconv_key: &'static str, value: impl Into<Value>) -> Labels {
+		let mut out = self.clone();
+		out.push(cadvisor_key, semconv_key, value);
+		out
+	}
+
+	pub fn extend(&mut self, other: Labels) {
+		self.cadvisor.extend(other.cadvisor);
+		self.semconv.extend(other.semconv);
+	}
+
+	pub fn shrink_to_fit(&mut self) {
+		self.cadvisor.shrink_to_fit();
+		self.semconv.shrink_to_fit();
+	}
+}
+
+/// the groups metrics are in, which can be disabled together
+static GROUPS: &[&str] = &[
+	"cfs",
+	"compose",
+	"cpu",
+	"fs",
+	"last_seen",
+	"memory",
+	"memory_failures",
+	"network",
+	"paused",
+	"start_time",
+	"swarm_service",
+	"threads",
+];
+
+/// every cAdvisor metric name, for checking disable_metrics
+static CADVISOR_NAMES: &[&str] = &[
+	"compose_project_containers",
+	"compose_project_cpu_usage_seconds_total",
+	"compose_project_memory_usage_bytes",
+	"compose_project_memory_working_set_bytes",
+	"compose_service_containers",
+	"compose_service_cpu_usage_seconds_total",
+	"compose_service_memory_usage_bytes",
+	"compose_service_memory_working_set_bytes",
+	"container_cpu_cfs_periods_total",
+	"container_cpu_cfs_throttled_periods_total",
+	"container_cpu_cfs_throttled_seconds_total",
+	"container_cpu_limit_utilization_ratio",
+	"container_cpu_system_seconds_total",
+	"container_cpu_usage_seconds_total",
+	"container_cpu_user_seconds_total",
+	"container_cpu_utilization_ratio",
+	"container_events_total",
+	"container_fs_reads_bytes_total",
+	"container_fs_writes_bytes_total",
+	"container_last_seen",
+	"container_memory_cache",
+	"container_memory_failures_total",
+	"container_memory_mapped_file",
+	"container_memory_rss",
+	"container_memory_usage_bytes",
+	"container_memory_utilization_ratio",
+	"container_memory_working_set_bytes",
+	"container_network_receive_bytes_total",
+	"container_network_receive_errors_total",
+	"container_network_receive_packets_dropped_total",
+	"container_network_receive_packets_total",
+	"container_network_transmit_bytes_total",
+	"container_network_transmit_errors_total",
+	"container_network_transmit_packets_dropped_total",
+	"container_network_transmit_packets_total",
+	"container_paused",
+	"container_start_time_seconds",
+	"container_threads",
+	"container_threads_max",
+	"docker_network_receive_bytes_total",
+	"docker_network_receive_errors_total",
+	"docker_network_receive_packets_dropped_total",
+	"docker_network_receive_packets_total",
+	"docker_network_transmit_bytes_total",
+	"docker_network_transmit_errors_total",
+	"docker_network_transmit_packets_dropped_total",
+	"docker_network_transmit_packets_total",
+	"swarm_service_cpu_usage_seconds_total",
+	"swarm_service_memory_usage_bytes",
+	"swarm_service_memory_working_set_bytes",
+	"swarm_service_tasks",
+];
+
+/// every semconv metric name, for checking disable_metrics
+static SEMCONV_NAMES: &[&str] = &[
+	"container.cpu.limit_utilization",
+	"container.cpu.throttling_data.periods",
+	"container.cpu.throttling_data.throttled_periods",
+	"container.cpu.throttling_data.throttled_time",
+	"container.cpu.time",
+	"container.cpu.utilization",
+	"container.disk.io",
+	"container.events",
+	"container.last_seen",
+	"container.memory.cache",
+	"container.memory.mapped_file",
+	"container.memory.pgfault",
+	"container.memory.pgmajfault",
+	"container.memory.rss",
+	"container.memory.usage",
+	"container.memory.utilization",
+	"container.memory.working_set",
+	"container.network.dropped",
+	"container.network.errors",
+	"container.network.io",
+	"container.network.packets",
+	"container.paused",
+	"container.pids.count",
+	"container.pids.limit",
+	"container.uptime",
+	"docker.compose.project.containers",
+	"docker.compose.project.cpu.time",
+	"docker.compose.project.memory.usage",
+	"docker.compose.project.memory.working_set",
+	"docker.compose.service.containers",
+	"docker.compose.service.cpu.time",
+	"docker.compose.service.memory.usage",
+	"docker.compose.service.memory.working_set",
+	"docker.network.dropped",
+	"docker.network.errors",
+	"docker.network.io",
+	"docker.network.packets",
+	"docker.swarm.service.cpu.time",
+	"docker.swarm.service.memory.usage",
+	"docker.swarm.service.memory.working_set",
+	"docker.swarm.service.tasks",
+];
+
+/// whether `name` is a group or metric name that disable_metrics can turn off
+pub fn is_known_name(name: &str) -> bool {
+	[GROUPS, CADVISOR_NAMES, SEMCONV_NAMES].iter().any(|names| names.contains(&name))
+}
+
+/// anything that can take a measurement, so that counters and gauges can share `Metric`
+pub trait Measure<T> {
+	fn measure(&self, value: T, attrs: &[KeyValue]);
+}
+
+impl<T> Measure<T> for Counter<T> {
+	fn measure(&self, value: T, attrs: &[KeyValue]) {
+		self.add(value, attrs)
+	}
+}
+
+impl<T> Measure<T> for Gauge<T> {
+	fn measure(&self, value: T, attrs: &[KeyValue]) {
+		self.record(value, attrs)
+	}
+}
+
+/// a metric that records to a cAdvisor instrument, a semconv instrument, or both, depending on the naming config.
+/// if a metric has no name in one scheme, it is just not emitted under that scheme.
+pub struct Metric<I> {
+	cadvisor: Option<I>,
+	semconv: Option<I>,
+	/// extra attributes for only the semconv instrument, e.g. a direction where cAdvisor has separate metrics
+	semconv_attrs: Vec<KeyValue>,
+}
+
+impl<I> Metric<I> {
+	pub fn record<T: Copy>(&self, value: T, labels: &Labels)
+	where
+		I: Measure<T>,
+	{
+		if let Some(inst) = &self.cadvisor {
+			inst.measure(value, &labels.cadvisor);
+		}
+
+		if let Some(inst) = &self.semconv {
+			if self.semconv_attrs.is_empty() {
+				inst.measure(value, &labels.semconv);
+			} else {
+				let mut attrs = Vec::with_capacity(labels.semconv.len() + self.semconv_attrs.len());
+				attrs.extend_from_slice(&labels.semconv);
+				attrs.extend_from_slice(&self.semconv_attrs);
+				inst.measure(value, &attrs);
+			}
+		}
+	}
+
+	/// records only to the cAdvisor instrument, for series that cAdvisor duplicates but semconv doesn't
+	pub fn record_cadvisor_only<T>(&self, value: T, attrs: &[KeyValue])
+	where
+		I: Measure<T>,
+	{
+		if let Some(inst) = &self.cadvisor {
+			inst.measure(value, attrs);
+		}
+	}
+}
+
+struct Name {
+	name: &'static str,
+	unit: &'static str,
+	description: &'static str,
+}
+
+pub struct MetricBuilder<'a> {
+	meter: &'a Meter,
+	naming: MetricNaming,
+	cadvisor: Option<Name>,
+	semconv: Option<Name>,
+	semconv_attrs: Vec<KeyValue>,
+	group: Option<&'static str>,
+	disabled: &'a [String],
+}
+
+pub fn builder(meter: &Meter, naming: MetricNaming) -> MetricBuilder<'_> {
+	MetricBuilder {
+		meter,
+		naming,
+		cadvisor: None,
+		semconv: None,
+		semconv_attrs: Vec::new(),
+		group: None,
+		disabled: &[],
+	}
+}
+
+// builds an instrument with the given names, for each naming scheme that is enabled
+macro_rules! build_instrument {
+	($self:ident, $method:ident) => {{
+		let build = |n: Name| {
+			let b = $self.meter.$method(n.name).with_description(n.description);
+			if n.unit.is_empty() { b.build() } else { b.with_unit(n.unit).build() }
+		};
+
+		let enabled = $self.is_enabled();
+
+		Metric {
+			cadvisor: $self.cadvisor.filter(|_| enabled && $self.naming != MetricNaming::Semconv).map(build),
+			semconv: $self.semconv.filter(|_| enabled && $self.naming != MetricNaming::Cadvisor).map(build),
+			semconv_attrs: $self.semconv_attrs,
+		}
+	}};
+}
+
+impl<'a> MetricBuilder<'a> {
+	/// the group of metrics this metric is in, so it can be disabled along with the rest of the group
+	pub fn group(mut self, group: &'static str) -> Self {
+		debug_assert!(is_known_name(group), "{group} is missing from GROUPS");
+		self.group = Some(group);
+		self
+	}
+
+	/// groups and metric names that are disabled. a disabled metric has no instruments, so records nothing.
+	pub fn disable(mut self, disabled: &'a [String]) -> Self {
+		self.disabled = disabled;
+		self
+	}
+
+	/// disabling either name of a metric disables both, so disable_metrics works the same whatever the naming
+	fn is_enabled(&self) -> bool {
+		let names = [self.group, self.cadvisor.as_ref().map(|n| n.name), self.semconv.as_ref().map(|n| n.name)];
+		!names.into_iter().flatten().any(|name| self.disabled.iter().any(|d| d == name))
+	}
+
+	/// the cAdvisor name for this metric. an empty unit means no unit, as cAdvisor leaves a lot of them off.
+	pub fn cadvisor(mut self, name: &'static str, unit: &'static str, description: &'static str) -> Self {
+		debug_assert!(is_known_name(name), "{name} is missing from CADVISOR_NAMES");
+		self.cadvisor = Some(Name { name, unit, description });
+		self
+	}
+
+	/// the semconv name for this metric, plus any attributes that distinguish it from other metrics with the same name
+	pub fn semconv<const N: usize>(
+		mut self,
+		name: &'static str,
+		unit: &'static str,
+		description: &'static str,
+		attrs: [KeyValue; N],
+	) -> Self {
+		debug_assert!(is_known_name(name), "{name} is missing from SEMCONV_NAMES");
+		self.semconv = Some(Name { name, unit, description });
+		self.semconv_attrs = attrs.into();
+		self
+	}
+
+	pub fn u64_counter(self) -> Metric<Counter<u64>> {
+		build_instrument!(self, u64_counter)
+	}
+
+	pub fn f64_counter(self) -> Metric<Counter<f64>> {
+		build_instrument!(self, f64_counter)
+	}
+
+	pub fn u64_gauge(self) -> Metric<Gauge<u64>> {
+		build_instrument!(self, u64_gauge)
+	}
+
+	pub fn f64_gauge(self) -> Metric<Gauge<f64>> {
+		build_instrument!(self, f64_gauge)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use regex::Regex;
+
+	#[test]
+	fn every_metric_name_is_known() {
+		// metrics are built all over the place, so find their names in the source
+		let name = Regex::new(r#"(?:builder|\.group|\.cadvisor|\.semconv)\(\s*"([^"]+)""#).unwrap();
+		let mut found = 0;
+
+		for entry in std::fs::read_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/src")).unwrap() {
+			let path = entry.unwrap().path();
+			if path.extension().is_none_or(|e| e != "rs") || path.ends_with("metrics.rs") {
+				continue;
+			}
+
+			let src = std::fs::read_to_string(&path).unwrap();
+			for caps in name.captures_iter(&src) {
+				assert!(is_known_name(&caps[1]), "{} in {} is missing from the known names", &caps[1], path.display());
+				found += 1;
+			}
+		}
+
+		assert!(found > 100, "only found {found} metric names");
+		assert!(!is_known_name("container_cpu"));
+	}
+}
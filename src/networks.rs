@@ -0,0 +1,238 @@
+// maps the interfaces in a container's stats to the docker networks they're attached to
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+
+use bollard::models::{ContainerInspectResponse, EndpointSettings};
+use bollard::network::InspectNetworkOptions;
+use bollard::Docker;
+
+use crate::metrics::Labels;
+use crate::s_log::*;
+use crate::self_metrics;
+
+/// drivers of the networks we've seen, by network id, as a network's driver can't change
+static NETWORK_DRIVERS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+#[derive(Clone, Debug)]
+pub struct Network {
+	pub name: String,
+	pub id: String,
+	pub driver: String,
+}
+
+impl Network {
+	/// the attributes identifying this network
+	pub fn labels(&self) -> Labels {
+		let mut out = Labels::default();
+		out.push("network", "docker.network.name", self.name.clone());
+		out.push("network_id", "docker.network.id", self.id.clone());
+		out.push("network_driver", "docker.network.driver", self.driver.clone());
+		out
+	}
+}
+
+/// finds the network for each of `interfaces` that we can, from the container's endpoints.
+///
+/// on windows, stats are keyed by endpoint id, so those match exactly. linux stats only have the interface name, so
+/// a container with one interface and one network is matched directly, and otherwise the interfaces' subnets are
+/// read from the container's routes, if the host's /proc is available at `host_proc`.
+pub async fn map_interfaces(
+	docker: &Docker,
+	inspect: &ContainerInspectResponse,
+	interfaces: &[&str],
+	host_proc: Option<&str>,
+) -> HashMap<String, Network> {
+	let endpoints = inspect
+		.network_settings
+		.as_ref()
+		.and_then(|n| n.networks.clone())
+		.unwrap_or_default();
+
+	let mut matched: HashMap<String, (&String, &EndpointSettings)> = HashMap::new();
+
+	for iface in interfaces {
+		if let Some(ep) = endpoints.iter().find(|(_, ep)| ep.endpoint_id.as_deref() == Some(iface)) {
+			matched.insert(iface.to_string(), ep);
+		}
+	}
+
+	if matched.is_empty() && interfaces.len() == 1 && endpoints.len() == 1 {
+		matched.insert(interfaces[0].to_owned(), endpoints.iter().next().unwrap());
+	}
+
+	if matched.len() < interfaces.len() {
+		let pid = inspect.state.as_ref().and_then(|s| s.pid).filter(|p| *p > 0);
+
+		if let (Some(host_proc), Some(pid)) = (host_proc, pid) {
+			for (iface, subnet) in read_subnets(host_proc, pid) {
+				if matched.contains_key(&iface) || !interfaces.contains(&iface.as_str()) {
+					continue;
+				}
+
+				if let Some(ep) = endpoint_in_subnet(&endpoints, subnet) {
+					matched.insert(iface, ep);
+				}
+			}
+		}
+	}
+
+	let mut out = HashMap::new();
+	for (iface, (name, ep)) in matched {
+		let Some(id) = ep.network_id.clone() else {
+			continue;
+		};
+
+		out.insert(
+			iface,
+			Network {
+				name: name.clone(),
+				driver: network_driver(docker, &id).await,
+				id,
+			},
+		);
+	}
+
+	out
+}
+
+async fn network_driver(docker: &Docker, id: &str) -> String {
+	if let Some(driver) = NETWORK_DRIVERS.lock().unwrap().get(id) {
+		return driver.clone();
+	}
+
+	let options: Option<InspectNetworkOptions<String>> = None;
+	match self_metrics::time_docker("inspect_network", docker.inspect_network(id, options)).await {
+		Ok(net) => {
+			let driver = net.driver.unwrap_or_default();
+			NETWORK_DRIVERS.lock().unwrap().insert(id.to_owned(), driver.clone());
+			driver
+		}
+		Err(err) => {
+			warn(format_args!("Failed to inspect network {id}: {err:?}"), [("network_id", id)]);
+			String::new()
+		}
+	}
+}
+
+/// the endpoint, and its network's name, that is on `subnet`
+fn endpoint_in_subnet(
+	endpoints: &HashMap<String, EndpointSettings>,
+	subnet: (Ipv4Addr, u32),
+) -> Option<(&String, &EndpointSettings)> {
+	endpoints.iter().find(|(_, ep)| endpoint_subnet(ep) == Some(subnet))
+}
+
+/// the network address and prefix length of an endpoint's IPv4 subnet
+fn endpoint_subnet(ep: &EndpointSettings) -> Option<(Ipv4Addr, u32)> {
+	let ip: Ipv4Addr = ep.ip_address.as_ref()?.parse().ok()?;
+	let prefix = u32::try_from(ep.ip_prefix_len?).ok().filter(|p| *p <= 32)?;
+
+	let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+	Some((Ipv4Addr::from(u32::from(ip) & mask), prefix))
+}
+
+/// the directly connected IPv4 subnets of each interface in a process' network namespace, from /proc/<pid>/net/route
+fn read_subnets(host_proc: &str, pid: i64) -> Vec<(String, (Ipv4Addr, u32))> {
+	let path = format!("{host_proc}/{pid}/net/route");
+	let routes = match std::fs::read_to_string(&path) {
+		Ok(r) => r,
+		Err(err) => {
+			debug(format_args!("Failed to read {path}: {err}"), [("path", &*path)]);
+			return Vec::new();
+		}
+	};
+
+	parse_routes(&routes)
+}
+
+/// parses a route table in the format of /proc/net/route, keeping only each interface's directly connected subnets
+fn parse_routes(routes: &str) -> Vec<(String, (Ipv4Addr, u32))> {
+	// Iface Destination Gateway Flags RefCnt Use Metric Mask ..., with addresses as little endian hex
+	let hex = |s: &str| u32::from_str_radix(s, 16).ok().map(|a| Ipv4Addr::from(a.to_le_bytes()));
+
+	routes
+		.lines()
+		.skip(1)
+		.filter_map(|line| {
+			let fields: Vec<&str> = line.split_whitespace().collect();
+			let (iface, dest, gateway, mask) =
+				(fields.first()?, hex(fields.get(1)?)?, hex(fields.get(2)?)?, hex(fields.get(7)?)?);
+
+			// only routes without a gateway are the interface's own subnet
+			if !gateway.is_unspecified() || dest.is_unspecified() {
+				return None;
+			}
+
+			Some((iface.to_string(), (dest, u32::from(mask).count_ones())))
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// a container on the default bridge and a user defined network, with its default route through the bridge
+	const ROUTES: &str = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+eth0\t00000000\t010011AC\t0003\t0\t0\t0\t00000000\t0\t0\t0
+eth0\t000011AC\t00000000\t0001\t0\t0\t0\t0000FFFF\t0\t0\t0
+eth1\t0014A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+eth1\t0000000A\t0114A8C0\t0003\t0\t0\t0\t000000FF\t0\t0\t0
+bad\tnot-hex\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0
+short\t0014A8C0
+";
+
+	fn endpoint(ip: &str, prefix: i64) -> EndpointSettings {
+		EndpointSettings {
+			ip_address: Some(ip.to_owned()),
+			ip_prefix_len: Some(prefix),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn parses_route_tables() {
+		assert_eq!(
+			parse_routes(ROUTES),
+			[
+				("eth0".to_owned(), (Ipv4Addr::new(172, 17, 0, 0), 16)),
+				("eth1".to_owned(), (Ipv4Addr::new(192, 168, 20, 0), 24)),
+			]
+		);
+		assert_eq!(parse_routes(""), []);
+	}
+
+	#[test]
+	fn finds_endpoint_subnets() {
+		assert_eq!(endpoint_subnet(&endpoint("172.17.0.2", 16)), Some((Ipv4Addr::new(172, 17, 0, 0), 16)));
+		assert_eq!(endpoint_subnet(&endpoint("10.1.2.3", 0)), Some((Ipv4Addr::UNSPECIFIED, 0)));
+		assert_eq!(endpoint_subnet(&endpoint("10.1.2.3", 32)), Some((Ipv4Addr::new(10, 1, 2, 3), 32)));
+
+		// no address, e.g. host or none networks, or one that isn't IPv4
+		assert_eq!(endpoint_subnet(&EndpointSettings::default()), None);
+		assert_eq!(endpoint_subnet(&endpoint("", 0)), None);
+		assert_eq!(endpoint_subnet(&endpoint("fd00::2", 64)), None);
+		assert_eq!(endpoint_subnet(&endpoint("10.1.2.3", 33)), None);
+	}
+
+	#[test]
+	fn matches_routes_to_endpoints() {
+		let subnets: HashMap<_, _> = parse_routes(ROUTES).into_iter().collect();
+		let network = |endpoints: &[(&str, EndpointSettings)], iface: &str| {
+			let endpoints: HashMap<_, _> = endpoints.iter().map(|(n, ep)| (n.to_string(), ep.clone())).collect();
+			endpoint_in_subnet(&endpoints, subnets[iface]).map(|(name, _)| name.clone())
+		};
+
+		let endpoints = [("bridge", endpoint("172.17.0.2", 16)), ("backend", endpoint("192.168.20.5", 24))];
+		assert_eq!(network(&endpoints, "eth0").as_deref(), Some("bridge"));
+		assert_eq!(network(&endpoints, "eth1").as_deref(), Some("backend"));
+
+		// same address as eth1, but a different prefix, so a different subnet
+		let endpoints = [("bridge", endpoint("172.17.0.2", 16)), ("wide", endpoint("192.168.20.5", 16))];
+		assert_eq!(network(&endpoints, "eth1"), None);
+		assert_eq!(network(&[("other", endpoint("10.0.0.5", 8))], "eth0"), None);
+	}
+}
@@ -1,33 +1,46 @@
 // containerspy structured logger
 
 use std::fmt::{Display, Formatter};
+use std::io::Write;
+use std::panic::Location;
+use std::str::FromStr;
+use std::sync::RwLock;
 use chrono::Utc;
+use serde::{Deserialize, Deserializer};
+use serde::de::value::{Error as DeError, StrDeserializer};
+use serde::de::Error as _;
 
-#[allow(dead_code)]
+use crate::log_sinks::{self, Journald, Syslog};
+
+#[track_caller]
 pub fn debug<'a>(args: impl Display, rich: impl IntoIterator<Item = (&'a str, &'a str)>) {
-	log_impl(LogLevel::Debug, args.to_string().as_str(), rich);
+	log_impl(LogLevel::Debug, Location::caller(), args, rich);
 }
 
+#[track_caller]
 pub fn info<'a>(args: impl Display, rich: impl IntoIterator<Item = (&'a str, &'a str)>) {
-	log_impl(LogLevel::Info, args.to_string().as_str(), rich);
+	log_impl(LogLevel::Info, Location::caller(), args, rich);
 }
 
-#[allow(dead_code)]
+#[track_caller]
 pub fn warn<'a>(args: impl Display, rich: impl IntoIterator<Item = (&'a str, &'a str)>) {
-	log_impl(LogLevel::Warn, args.to_string().as_str(), rich);
+	log_impl(LogLevel::Warn, Location::caller(), args, rich);
 }
 
-#[allow(dead_code)]
+#[track_caller]
 pub fn error<'a>(args: impl Display, rich: impl IntoIterator<Item = (&'a str, &'a str)>) {
-	log_impl(LogLevel::Error, args.to_string().as_str(), rich);
+	log_impl(LogLevel::Error, Location::caller(), args, rich);
 }
 
-#[allow(dead_code)]
+#[track_caller]
 pub fn fatal<'a>(args: impl Display, rich: impl IntoIterator<Item = (&'a str, &'a str)>) {
-	log_impl(LogLevel::Fatal, args.to_string().as_str(), rich);
+	log_impl(LogLevel::Fatal, Location::caller(), args, rich);
 }
 
-enum LogLevel {
+/// in order of severity, so that a level lets through itself and everything before it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
 	Fatal,
 	Error,
 	Warn,
@@ -49,50 +62,412 @@ impl Display for LogLevel {
 	}
 }
 
-fn log_impl<'a>(level: LogLevel, msg: &str, rich: impl IntoIterator<Item = (&'a str, &'a str)>) {
+impl FromStr for LogLevel {
+	type Err = DeError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		Self::deserialize(StrDeserializer::<DeError>::new(s))
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+	Logfmt,
+	Json,
+	/// for reading in a terminal, not for log aggregation
+	Human,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOutput {
+	Stdout,
+	Stderr,
+	/// systemd-journald's native protocol, with rich key/values as journal fields. ignores the format.
+	Journald,
+	/// RFC 5424 syslog to log_syslog_address. ignores the format.
+	Syslog,
+}
+
+/// a minimum log level for one module, written as module=level, e.g. stats_task=debug
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModuleLevel {
+	pub module: String,
+	pub level: LogLevel,
+}
+
+impl FromStr for ModuleLevel {
+	type Err = DeError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let (module, level) = s
+			.split_once('=')
+			.ok_or_else(|| DeError::custom(format!("expected module=level, got {s:?}")))?;
+
+		Ok(ModuleLevel {
+			module: module.trim().to_owned(),
+			level: level.trim().parse()?,
+		})
+	}
+}
+
+impl<'de> Deserialize<'de> for ModuleLevel {
+	fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+		let s = String::deserialize(d)?;
+		s.parse().map_err(D::Error::custom)
+	}
+}
+
+/// where log lines end up, with any sockets already opened
+enum Sink {
+	Stdout,
+	Stderr,
+	Journald(Journald),
+	Syslog(Syslog),
+}
+
+struct LogSettings {
+	format: LogFormat,
+	level: LogLevel,
+	sink: Sink,
+	modules: Vec<ModuleLevel>,
+}
+
+// used until the config is loaded, so config errors are still logged
+static SETTINGS: RwLock<LogSettings> = RwLock::new(LogSettings {
+	format: LogFormat::Logfmt,
+	level: LogLevel::Info,
+	sink: Sink::Stdout,
+	modules: Vec::new(),
+});
+
+/// syslog_address is only used for LogOutput::Syslog. if the journald or syslog socket can't be opened, logs go to
+/// stderr.
+pub fn configure(format: LogFormat, level: LogLevel, output: LogOutput, syslog_address: &str, modules: &[ModuleLevel]) {
+	let sink = match output {
+		LogOutput::Stdout => Ok(Sink::Stdout),
+		LogOutput::Stderr => Ok(Sink::Stderr),
+		LogOutput::Journald => Journald::connect().map(Sink::Journald),
+		LogOutput::Syslog => Syslog::connect(syslog_address).map(Sink::Syslog),
+	};
+
+	let (sink, err) = match sink {
+		Ok(s) => (s, None),
+		Err(e) => (Sink::Stderr, Some(e)),
+	};
+
+	*SETTINGS.write().unwrap() = LogSettings {
+		format,
+		level,
+		sink,
+		modules: modules.to_vec(),
+	};
+
+	if let Some(e) = err {
+		let output = format!("{output:?}").to_lowercase();
+		error(format_args!("Failed to open {output} log output, logging to stderr instead: {e}"), [("output", &*output)]);
+	}
+}
+
+/// gets the module a log call came from using its source file, e.g. src/stats_task.rs -> stats_task
+fn module_of(loc: &Location) -> String {
+	let file = loc.file().replace('\\', "/");
+	let file = file.strip_prefix("src/").unwrap_or(&file);
+	let file = file.strip_suffix(".rs").unwrap_or(file);
+	let file = file.strip_suffix("/mod").unwrap_or(file);
+
+	file.replace('/', "::")
+}
+
+impl LogSettings {
+	fn enabled(&self, level: LogLevel, module: &str) -> bool {
+		// the most specific override wins
+		let min = self
+			.modules
+			.iter()
+			.filter(|m| module == m.module || module.starts_with(&format!("{}::", m.module)))
+			.max_by_key(|m| m.module.len())
+			.map_or(self.level, |m| m.level);
+
+		level <= min
+	}
+}
+
+fn log_impl<'a>(
+	level: LogLevel,
+	loc: &Location,
+	args: impl Display,
+	rich: impl IntoIterator<Item = (&'a str, &'a str)>,
+) {
+	let settings = SETTINGS.read().unwrap();
+
+	let module = module_of(loc);
+	if !settings.enabled(level, &module) {
+		return;
+	}
+
+	let msg = args.to_string();
+	let msg = msg.as_str();
+
 	let time = Utc::now();
 	let nice_time = time.format("%F %X%.3f").to_string();
 	let full_time = time.format("%+").to_string();
 	let level_str = level.to_string();
 
-	let final_rich: Vec<(&str, &str)> = vec![
+	let mut all_rich_elements: Vec<(&str, &str)> = vec![
 		("ts", full_time.as_str()),
 		("level", level_str.as_str()),
 		("msg", msg)
 	];
-	// Use map to "forget" the lifetime of rich elements
-	let iter = final_rich.into_iter();
-	let arg_iter = rich.into_iter().map(|x|x);
-	// Combine the two rich element iterators
-	let all_rich_elements = iter.chain(arg_iter);
-
-	let mut buf = nice_time;
-	for (k, v) in all_rich_elements {
-		if needs_escaping(&k) {
-			continue;
+	// pushed one by one, as the caller's elements live longer than ours, so can't be chained onto them
+	for (key, val) in rich {
+		all_rich_elements.push((key, val));
+	}
+
+	log_sinks::send_otlp(level, &module, loc.file(), loc.line(), &all_rich_elements);
+
+	// these do their own encoding
+	match &settings.sink {
+		Sink::Journald(j) => return j.send(level, loc.file(), loc.line(), &all_rich_elements),
+		Sink::Syslog(s) => return s.send(level, &all_rich_elements),
+		Sink::Stdout | Sink::Stderr => {}
+	}
+
+	let all_rich_elements = all_rich_elements.into_iter();
+
+	let buf = match settings.format {
+		LogFormat::Logfmt => encode_logfmt(all_rich_elements),
+		LogFormat::Json => {
+			let fields: Vec<_> = all_rich_elements
+				.map(|(k, v)| format!("{}:{}", serde_json::to_string(k).unwrap(), serde_json::to_string(v).unwrap()))
+				.collect();
+			format!("{{{}}}", fields.join(","))
+		}
+		LogFormat::Human => {
+			// ts, level, and msg are already shown in a nicer way
+			let mut buf = format!("{nice_time} {:<5} {msg}", level_str.to_uppercase());
+			for (k, v) in all_rich_elements.skip(3) {
+				buf += &format!(" {k}={v:?}");
+			}
+			buf
 		}
+	};
+
+	// ignore failed writes, there's nowhere left to log them to
+	let _ = match settings.sink {
+		Sink::Stderr => writeln!(std::io::stderr().lock(), "{buf}"),
+		_ => writeln!(std::io::stdout().lock(), "{buf}"),
+	};
+}
 
-		if needs_escaping(&v) {
-			buf += &format!(" {k}=\"{}\"", escape(&v));
-		} else {
-			buf += &format!(" {k}={v}");
+/// encodes key value pairs as one logfmt line, as specified by https://pkg.go.dev/github.com/go-logfmt/logfmt
+fn encode_logfmt<'a>(pairs: impl IntoIterator<Item = (&'a str, &'a str)>) -> String {
+	let mut buf = String::new();
+
+	for (k, v) in pairs {
+		if !buf.is_empty() {
+			buf.push(' ');
 		}
+
+		buf += &logfmt_key(k);
+		buf.push('=');
+		write_logfmt_value(&mut buf, v);
 	}
 
-	println!("{buf}");
+	buf
+}
+
+/// if a char can appear in a key or an unquoted value
+fn is_bare_char(c: char) -> bool {
+	!c.is_whitespace() && !c.is_control() && c != '=' && c != '"'
 }
 
-static SAFE_ALPHABET: &str = r#"abcdefghijklmnopqrstuvxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789-_+.,/\\|!@#$%^&*()[]{}"#;
+/// keys can't be quoted, so anything not allowed in one is replaced with an underscore rather than dropping the key
+fn logfmt_key(key: &str) -> String {
+	if key.is_empty() {
+		return "_".to_owned();
+	}
+
+	key.chars().map(|c| if is_bare_char(c) { c } else { '_' }).collect()
+}
 
-fn needs_escaping(val: &str) -> bool {
-	for char in val.chars() {
-		if !SAFE_ALPHABET.contains(char) {
-			return true
+fn write_logfmt_value(buf: &mut String, val: &str) {
+	if !val.is_empty() && val.chars().all(is_bare_char) {
+		buf.push_str(val);
+		return;
+	}
+
+	buf.push('"');
+	for c in val.chars() {
+		match c {
+			'\\' => buf.push_str("\\\\"),
+			'"' => buf.push_str("\\\""),
+			'\n' => buf.push_str("\\n"),
+			'\r' => buf.push_str("\\r"),
+			'\t' => buf.push_str("\\t"),
+			c if c.is_control() => buf.push_str(&format!("\\u{:04x}", c as u32)),
+			c => buf.push(c),
 		}
 	}
-	false
+	buf.push('"');
 }
 
-fn escape(val: &str) -> String {
-	val.replace("\n", "\\n").replace("\\", "\\\\").replace("\"", "\\\"")
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use proptest::prelude::*;
+
+	/// a logfmt decoder following go-logfmt, written separately from the encoder so that they check each other
+	fn parse_logfmt(line: &str) -> Result<Vec<(String, String)>, String> {
+		let mut pairs = Vec::new();
+		let mut chars = line.chars().peekable();
+
+		loop {
+			while chars.next_if(|c| *c <= ' ').is_some() {}
+			if chars.peek().is_none() {
+				return Ok(pairs);
+			}
+
+			let mut key = String::new();
+			while let Some(c) = chars.next_if(|c| *c > ' ' && *c != '=') {
+				if c == '"' {
+					return Err(format!("unexpected quote in key {key:?}"));
+				}
+				key.push(c);
+			}
+
+			if key.is_empty() {
+				return Err("empty key".to_owned());
+			}
+
+			let mut val = String::new();
+			if chars.next_if_eq(&'=').is_some() {
+				if chars.next_if_eq(&'"').is_some() {
+					loop {
+						match chars.next().ok_or("unterminated quoted value")? {
+							'"' => break,
+							'\\' => match chars.next().ok_or("unterminated escape")? {
+								'\\' => val.push('\\'),
+								'"' => val.push('"'),
+								'n' => val.push('\n'),
+								'r' => val.push('\r'),
+								't' => val.push('\t'),
+								'u' => {
+									let hex: String = chars.by_ref().take(4).collect();
+									let code = u32::from_str_radix(&hex, 16).map_err(|e| e.to_string())?;
+									val.push(char::from_u32(code).ok_or("invalid \\u escape")?);
+								}
+								c => return Err(format!("unknown escape \\{c}")),
+							},
+							c => val.push(c),
+						}
+					}
+				} else {
+					while let Some(c) = chars.next_if(|c| *c > ' ') {
+						if c == '"' || c == '=' {
+							return Err(format!("unexpected {c:?} in unquoted value"));
+						}
+						val.push(c);
+					}
+				}
+			}
+
+			pairs.push((key, val));
+		}
+	}
+
+	#[test]
+	fn bare_values_are_not_quoted() {
+		// w used to be missing from the safe characters
+		assert_eq!(
+			encode_logfmt([("msg", "welcome"), ("path", "/var/run/docker.sock")]),
+			"msg=welcome path=/var/run/docker.sock"
+		);
+	}
+
+	#[test]
+	fn escapes_are_not_doubled() {
+		assert_eq!(encode_logfmt([("msg", "a\nb\\c\"d")]), r#"msg="a\nb\\c\"d""#);
+	}
+
+	#[test]
+	fn invalid_keys_are_kept() {
+		assert_eq!(encode_logfmt([("a key", "1"), ("", "2"), ("x=\"y\"", "3")]), "a_key=1 _=2 x__y_=3");
+	}
+
+	#[test]
+	fn empty_values_are_quoted() {
+		assert_eq!(encode_logfmt([("a", ""), ("b", "c")]), r#"a="" b=c"#);
+	}
+
+	fn settings(level: LogLevel, modules: &[&str]) -> LogSettings {
+		LogSettings {
+			format: LogFormat::Logfmt,
+			level,
+			sink: Sink::Stdout,
+			modules: modules.iter().map(|m| m.parse().unwrap()).collect(),
+		}
+	}
+
+	#[test]
+	fn parses_module_levels() {
+		assert_eq!(
+			" stats_task = debug ".parse::<ModuleLevel>().unwrap(),
+			ModuleLevel { module: "stats_task".to_owned(), level: LogLevel::Debug }
+		);
+
+		let missing_eq = "stats_task".parse::<ModuleLevel>().unwrap_err().to_string();
+		assert!(missing_eq.contains("expected module=level"), "{missing_eq}");
+
+		let bad_level = "stats_task=verbose".parse::<ModuleLevel>().unwrap_err().to_string();
+		assert!(bad_level.contains("verbose"), "{bad_level}");
+		assert!("stats_task=".parse::<ModuleLevel>().is_err());
+	}
+
+	#[test]
+	fn module_levels_override_the_global_level() {
+		let s = settings(LogLevel::Info, &["stats_task=debug", "watcher=error"]);
+
+		assert!(s.enabled(LogLevel::Debug, "stats_task"));
+		assert!(!s.enabled(LogLevel::Debug, "main"));
+		assert!(s.enabled(LogLevel::Info, "main"));
+		// an override can be stricter than the global level too
+		assert!(!s.enabled(LogLevel::Warn, "watcher"));
+		assert!(s.enabled(LogLevel::Error, "watcher"));
+		// only whole module names match, not prefixes
+		assert!(!s.enabled(LogLevel::Debug, "stats_task_extra"));
+	}
+
+	#[test]
+	fn the_most_specific_module_level_wins() {
+		let s = settings(LogLevel::Warn, &["engine::podman=error", "engine=debug"]);
+
+		assert!(s.enabled(LogLevel::Debug, "engine"));
+		assert!(s.enabled(LogLevel::Debug, "engine::docker"));
+		assert!(!s.enabled(LogLevel::Warn, "engine::podman"));
+		assert!(!s.enabled(LogLevel::Info, "main"));
+	}
+
+	proptest! {
+		#[test]
+		fn round_trips(pairs in prop::collection::vec((any::<String>(), any::<String>()), 0..8)) {
+			let line = encode_logfmt(pairs.iter().map(|(k, v)| (k.as_str(), v.as_str())));
+			let expected: Vec<_> = pairs.iter().map(|(k, v)| (logfmt_key(k), v.clone())).collect();
+
+			prop_assert!(!line.contains('\n'));
+			prop_assert_eq!(parse_logfmt(&line), Ok(expected));
+		}
+
+		#[test]
+		fn valid_keys_are_unchanged(key in "[^\\s\\p{Cc}=\"]+") {
+			prop_assert_eq!(logfmt_key(&key), key);
+		}
+
+		#[test]
+		fn values_round_trip_with_special_chars(val in "[ a-z=\"\\\\\\n\\r\\t\\x00-\\x1f\\u{7f}-\\u{9f}\\u{2028}]*") {
+			let line = encode_logfmt([("k", val.as_str())]);
+			prop_assert_eq!(parse_logfmt(&line), Ok(vec![("k".to_owned(), val)]));
+		}
+	}
+}
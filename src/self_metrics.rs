@@ -0,0 +1,225 @@
+// containerspy_* metrics about containerspy itself
+
+use std::future::Future;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use opentelemetry::metrics::{Counter, Gauge, Histogram, Meter, ObservableCounter, ObservableGauge};
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::error::OTelSdkResult;
+use opentelemetry_sdk::metrics::data::ResourceMetrics;
+use opentelemetry_sdk::metrics::exporter::PushMetricExporter;
+use opentelemetry_sdk::metrics::Temporality;
+
+use crate::{built_info, health};
+
+pub struct SelfMetrics {
+	watched_containers: Gauge<u64>,
+	worker_launches: Counter<u64>,
+	worker_terminations: Counter<u64>,
+	stream_errors: Counter<u64>,
+	docker_api_duration: Histogram<f64>,
+	exports: Counter<u64>,
+	export_duration: Histogram<f64>,
+	container_log_records: Counter<u64>,
+	// observable instruments only need to be kept around, their callbacks do the recording
+	_process_rss: ObservableGauge<u64>,
+	_process_cpu: ObservableCounter<f64>,
+	_build_info: ObservableGauge<u64>,
+}
+
+static SELF_METRICS: RwLock<Option<Arc<SelfMetrics>>> = RwLock::new(None);
+
+/// creates the self metrics on the given meter, replacing any from a previous meter provider
+pub fn init(meter: &Meter) {
+	let latency_buckets = vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+	let metrics = SelfMetrics {
+		watched_containers: meter
+			.u64_gauge("containerspy_watched_containers")
+			.with_description("Number of containers currently being watched")
+			.build(),
+		worker_launches: meter
+			.u64_counter("containerspy_worker_launches_total")
+			.with_description("Number of container workers launched")
+			.build(),
+		worker_terminations: meter
+			.u64_counter("containerspy_worker_terminations_total")
+			.with_description("Number of container workers stopped, by reason")
+			.build(),
+		stream_errors: meter
+			.u64_counter("containerspy_stats_stream_errors_total")
+			.with_description("Number of errors reading a container's stats stream")
+			.build(),
+		docker_api_duration: meter
+			.f64_histogram("containerspy_docker_api_duration_seconds")
+			.with_unit("s")
+			.with_description("Latency of Docker API calls, by operation")
+			.with_boundaries(latency_buckets.clone())
+			.build(),
+		exports: meter
+			.u64_counter("containerspy_exports_total")
+			.with_description("Number of OTLP metric exports, by result")
+			.build(),
+		export_duration: meter
+			.f64_histogram("containerspy_export_duration_seconds")
+			.with_unit("s")
+			.with_description("Duration of OTLP metric exports")
+			.with_boundaries(latency_buckets)
+			.build(),
+		container_log_records: meter
+			.u64_counter("containerspy_container_log_records_total")
+			.with_description(
+				"Number of container log records read, by whether they were forwarded or dropped by the rate limit",
+			)
+			.build(),
+		_process_rss: meter
+			.u64_observable_gauge("containerspy_process_resident_memory_bytes")
+			.with_unit("By")
+			.with_description("Resident memory size of containerspy")
+			.with_callback(|o| {
+				if let Some(rss) = process_rss() {
+					o.observe(rss, &[]);
+				}
+			})
+			.build(),
+		_process_cpu: meter
+			.f64_observable_counter("containerspy_process_cpu_seconds_total")
+			.with_unit("s")
+			.with_description("Total user and system cpu time used by containerspy")
+			.with_callback(|o| {
+				if let Some(cpu) = process_cpu_seconds() {
+					o.observe(cpu, &[]);
+				}
+			})
+			.build(),
+		_build_info: meter
+			.u64_observable_gauge("containerspy_build_info")
+			.with_description("Always 1, labelled with the containerspy version and git commit")
+			.with_callback(|o| {
+				o.observe(
+					1,
+					&[
+						KeyValue::new("version", built_info::PKG_VERSION),
+						KeyValue::new("git_commit", built_info::GIT_COMMIT_HASH.unwrap_or("unknown")),
+					],
+				)
+			})
+			.build(),
+	};
+
+	*SELF_METRICS.write().unwrap() = Some(Arc::new(metrics));
+}
+
+fn with(f: impl FnOnce(&SelfMetrics)) {
+	let metrics = SELF_METRICS.read().unwrap().clone();
+	if let Some(m) = metrics {
+		f(&m);
+	}
+}
+
+/// the number of containers watched on one engine, labelled by its docker_host if watching several
+pub fn watched_containers(host: Option<&str>, count: usize) {
+	let attrs: Vec<_> = host.map(|h| KeyValue::new("docker_host", h.to_owned())).into_iter().collect();
+	with(|m| m.watched_containers.record(count as u64, &attrs));
+}
+
+pub fn worker_launched() {
+	with(|m| m.worker_launches.add(1, &[]));
+}
+
+/// reason is why the worker stopped, e.g. the container was removed, or the stats stream ended
+pub fn worker_terminated(reason: &'static str, count: usize) {
+	with(|m| m.worker_terminations.add(count as u64, &[KeyValue::new("reason", reason)]));
+}
+
+pub fn stream_error(container_id: &str) {
+	with(|m| m.stream_errors.add(1, &[KeyValue::new("id", container_id.to_owned())]));
+}
+
+/// result is forwarded or dropped
+pub fn container_log_record(container_id: &str, result: &'static str) {
+	with(|m| {
+		m.container_log_records
+			.add(1, &[KeyValue::new("id", container_id.to_owned()), KeyValue::new("result", result)])
+	});
+}
+
+/// runs a Docker API call, recording how long it took
+pub async fn time_docker<T>(operation: &'static str, call: impl Future<Output = T>) -> T {
+	let start = Instant::now();
+	let res = call.await;
+
+	with(|m| {
+		m.docker_api_duration
+			.record(start.elapsed().as_secs_f64(), &[KeyValue::new("operation", operation)])
+	});
+
+	res
+}
+
+/// an exporter that records the result and duration of each export it does
+#[derive(Debug)]
+pub struct TimedExporter<E>(pub E);
+
+impl<E: PushMetricExporter> PushMetricExporter for TimedExporter<E> {
+	async fn export(&self, metrics: &mut ResourceMetrics) -> OTelSdkResult {
+		let start = Instant::now();
+		let res = self.0.export(metrics).await;
+
+		if res.is_ok() {
+			health::exported();
+		}
+
+		with(|m| {
+			let result = if res.is_ok() { "success" } else { "failure" };
+			m.exports.add(1, &[KeyValue::new("result", result)]);
+			m.export_duration.record(start.elapsed().as_secs_f64(), &[]);
+		});
+
+		res
+	}
+
+	fn force_flush(&self) -> OTelSdkResult {
+		self.0.force_flush()
+	}
+
+	fn shutdown(&self) -> OTelSdkResult {
+		self.0.shutdown()
+	}
+
+	fn temporality(&self) -> Temporality {
+		self.0.temporality()
+	}
+}
+
+#[cfg(target_os = "linux")]
+fn process_rss() -> Option<u64> {
+	// second field is resident pages
+	let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+	let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+
+	let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+	Some(pages * u64::try_from(page_size).ok()?)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn process_rss() -> Option<u64> {
+	None
+}
+
+#[cfg(unix)]
+fn process_cpu_seconds() -> Option<f64> {
+	let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+	if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+		return None;
+	}
+
+	let secs = |t: libc::timeval| t.tv_sec as f64 + t.tv_usec as f64 / 1_000_000.0;
+	Some(secs(usage.ru_utime) + secs(usage.ru_stime))
+}
+
+#[cfg(not(unix))]
+fn process_cpu_seconds() -> Option<f64> {
+	None
+}
@@ -1,46 +1,58 @@
-use bollard::container::{BlkioStatsEntry, MemoryStatsStats, Stats, StatsOptions};
-use bollard::models::ContainerSummary;
-use bollard::Docker;
+use bollard::container::{BlkioStatsEntry, CPUStats, MemoryStatsStats, Stats, StatsOptions};
+use bollard::models::{ContainerInspectResponse, ContainerSummary};
 use opentelemetry::metrics::Meter;
 use opentelemetry::KeyValue;
+use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::watch;
 use tokio::task::JoinHandle;
 use tokio_stream::StreamExt;
 use crate::s_log::*;
+use crate::engine::{Connection, StatsAdapter};
+use crate::metrics::Labels;
+use crate::totals::{GroupMetrics, Member};
+use crate::{compose, config, health, labels, metrics, networks, self_metrics, swarm};
 
 // I do not enjoy taking a bunch of Rcs but tokio needs ownership so fine.
 pub fn launch_stats_task(
 	container: ContainerSummary,
-	docker: Arc<Docker>,
-	meter: Arc<Meter>
+	conn: Arc<Connection>,
+	meter: Arc<Meter>,
+	paused_rx: watch::Receiver<bool>,
+	inspection: labels::SharedInspect,
 ) -> JoinHandle<()> {
 	tokio::spawn(async move {
 		// extract some container info
-		let container_id = container.id.unwrap();
-		let container_name = container
-			.names
-			.iter()
-			.flatten()
-			.next()
-			.map(|n| n.trim_start_matches("/").to_owned());
-
-		let mut stats_stream = docker.stats(
-			container_id.as_str(),
-			Some(StatsOptions {
-				stream: true,
-				one_shot: false,
-			}),
-		);
+		let container_id = container.id.clone().unwrap();
+		health::container_started(&container_id, labels::container_name(&container));
+
+		let docker = &conn.docker;
+
+		let mut adapter = StatsAdapter::new(conn.engine);
+		let mut stats_stream = docker
+			.stats(
+				container_id.as_str(),
+				Some(StatsOptions {
+					stream: true,
+					one_shot: false,
+				}),
+			)
+			.map(move |st| st.map(|st| adapter.adapt(st)));
 
 		// use the first read only for stats diffing for blkio - don't need for cpu thanks to precpu.
 		let first_read = loop {
-			match stats_stream.next().await {
-				None => return,
+			match self_metrics::time_docker("stats", stats_stream.next()).await {
+				None => {
+					self_metrics::worker_terminated("stream_ended", 1);
+					return;
+				}
 				Some(Ok(st)) => {
 					break st;
 				}
 				Some(Err(err)) => {
+					self_metrics::stream_error(&container_id);
+					health::container_error(&container_id, err.to_string());
 					error(format_args!("Failed to get stats for container {container_id}!: {err:?}"), [("container_id", &*container_id)]);
 				}
 			}
@@ -55,197 +67,487 @@ pub fn launch_stats_task(
 
 		let mut last_io_stats = blkio_stats.io_service_bytes_recursive;
 
+		// the cpu limit, env vars, and networks aren't in the container summary.
+		// the log worker shares this, so the container is only inspected once
+		let details = labels::inspect_once(&inspection, docker, &container_id).await;
+
+		let config = config::current();
+
 		// container labels shared for all metrics
-		let mut shared_labels = vec![
-			KeyValue::new("id", container_id.to_owned()),
-			KeyValue::new(
-				"image",
-				container.image.unwrap_or(container.image_id.unwrap()),
-			),
-		];
-
-		if let Some(name) = container_name {
-			shared_labels.push(KeyValue::new("name", name));
-		}
+		let mut shared_labels = labels::container_attributes(&container, details, &conn, &config);
+
+		let naming = config.metric_naming;
+		let utilization = config.utilization_metrics;
+		let disabled = config.disable_metrics.clone();
+		let host_proc = config.host_proc.clone();
+
+		// the swarm service and compose project this container is part of, if we export totals for them
+		let swarm_task = container
+			.labels
+			.as_ref()
+			.and_then(swarm::Task::from_labels)
+			.filter(|_| config.swarm_service_metrics);
+		let compose_container = container
+			.labels
+			.as_ref()
+			.and_then(compose::Container::from_labels)
+			.filter(|_| config.compose_metrics);
+
+		// don't keep an old config alive for the life of the worker
+		drop(config);
+
+		// only read once, so a `docker update` of the limit needs a restart of containerspy to be picked up
+		let cpu_limit = details.and_then(cpu_limit).filter(|_| utilization);
+
+		// the inspect from startup is still current the first time the interfaces are mapped
+		let mut startup_details = details;
+
+		// containers in the host's or another container's network namespace report that namespace's interfaces, which
+		// would be counted again for every container in it, so these are left to the host or the namespace's owner
+		let network_mode = container.host_config.as_ref().and_then(|h| h.network_mode.clone()).unwrap_or_default();
+		let (network_mode, shared_netns) = match network_mode.as_str() {
+			"host" => ("host".to_owned(), true),
+			m if m.starts_with("container:") => ("container".to_owned(), true),
+			_ => (network_mode, false),
+		};
 
-		if let Some(docker_labels) = &container.labels {
-			for (key, value) in docker_labels {
-				shared_labels.push(KeyValue::new(
-					"container_label_".to_string() + key,
-					value.clone(),
-				))
-			}
+		if shared_netns {
+			debug(
+				format_args!(
+					"Not exporting network metrics for container {container_id}, as it uses network mode {network_mode}"
+				),
+				[("container_id", &*container_id), ("network_mode", &*network_mode)],
+			);
 		}
 
-		// other label sets that are static per container
+		// labels for each interface, plus the labels of its docker network for the per-network metrics, if we could
+		// find its network. remapped whenever the interfaces change, e.g. from `docker network connect`
+		let mut interfaces: Vec<String> = Vec::new();
+		let mut interface_labels: HashMap<String, (Labels, Option<Labels>)> = HashMap::new();
+
+		// other label sets that are static per container.
+		// semconv has separate metrics for each failure type, and doesn't duplicate them by scope
 		let mut labels_mem_container_min_c = shared_labels.clone();
-		labels_mem_container_min_c.push(KeyValue::new("failure_type", "pgfault"));
+		labels_mem_container_min_c.cadvisor.push(KeyValue::new("failure_type", "pgfault"));
 
 		let mut labels_mem_container_maj_c = shared_labels.clone();
-		labels_mem_container_maj_c.push(KeyValue::new("failure_type", "pgmajfault"));
+		labels_mem_container_maj_c.cadvisor.push(KeyValue::new("failure_type", "pgmajfault"));
 
-		let mut labels_mem_container_min_h = labels_mem_container_min_c.clone();
+		let mut labels_mem_container_min_h = labels_mem_container_min_c.cadvisor.clone();
 		labels_mem_container_min_h.push(KeyValue::new("scope", "hierarchy"));
-		labels_mem_container_min_c.push(KeyValue::new("scope", "container"));
+		labels_mem_container_min_c.cadvisor.push(KeyValue::new("scope", "container"));
 
-		let mut labels_mem_container_maj_h = labels_mem_container_maj_c.clone();
+		let mut labels_mem_container_maj_h = labels_mem_container_maj_c.cadvisor.clone();
 		labels_mem_container_maj_h.push(KeyValue::new("scope", "hierarchy"));
-		labels_mem_container_maj_c.push(KeyValue::new("scope", "container"));
+		labels_mem_container_maj_c.cadvisor.push(KeyValue::new("scope", "container"));
 
 		// free space and make immutable
 		shared_labels.shrink_to_fit();
-		let shared_labels = &shared_labels[..];
+		let shared_labels = &shared_labels;
 
 		labels_mem_container_min_c.shrink_to_fit();
 		labels_mem_container_min_h.shrink_to_fit();
 		labels_mem_container_maj_c.shrink_to_fit();
 		labels_mem_container_maj_h.shrink_to_fit();
-		let labels_mem_container_min_c = &labels_mem_container_min_c[..];
+		let labels_mem_container_min_c = &labels_mem_container_min_c;
 		let labels_mem_container_min_h = &labels_mem_container_min_h[..];
-		let labels_mem_container_maj_c = &labels_mem_container_maj_c[..];
+		let labels_mem_container_maj_c = &labels_mem_container_maj_c;
 		let labels_mem_container_maj_h = &labels_mem_container_maj_h[..];
 
 		//println!("Starting reporting for container: {shared_labels:?}");
 
-		// create meters
-		let meter_container_cpu_usage_seconds_total = meter
-			.f64_counter("container_cpu_usage_seconds_total")
-			.with_unit("s")
-			.with_description("Cumulative cpu time consumed")
-			.build();
-		let meter_container_cpu_user_seconds_total = meter
-			.f64_counter("container_cpu_user_seconds_total")
-			.with_unit("s")
-			.with_description("Cumulative userland cpu time consumed")
-			.build();
-		let meter_container_cpu_system_seconds_total = meter
-			.f64_counter("container_cpu_system_seconds_total")
-			.with_unit("s")
-			.with_description("Cumulative kernel cpu time consumed")
-			.build();
-
-		let meter_container_cpu_cfs_periods_total = meter
-			.u64_counter("container_cpu_cfs_periods_total")
-			.with_description("Number of elapsed enforcement period intervals")
-			.build();
-		let meter_container_cpu_cfs_throttled_periods_total = meter
-			.u64_counter("container_cpu_cfs_throttled_periods_total")
-			.with_description("Number of throttled period intervals")
-			.build();
-		let meter_container_cpu_cfs_throttled_seconds_total = meter
-			.f64_counter("container_cpu_cfs_throttled_seconds_total")
-			.with_unit("s")
-			.with_description("Total time duration the container has been throttled")
-			.build();
-
-		let meter_container_fs_reads_bytes_total = meter
-			.u64_counter("container_fs_reads_bytes_total")
-			.with_unit("By")
-			.with_description("Cumulative bytes read")
-			.build();
-		let meter_container_fs_writes_bytes_total = meter
-			.u64_counter("container_fs_writes_bytes_total")
-			.with_unit("By")
-			.with_description("Cumulative bytes written")
-			.build();
-
-		let meter_container_last_seen = meter
-			.u64_gauge("container_last_seen")
-			.with_description("Last time this container was seen by ContainerSpy")
-			.build();
+		// create meters, in groups that can be turned off with disable_metrics.
+		// semconv names are from https://opentelemetry.io/docs/specs/semconv/system/container-metrics/ where they exist,
+		// else from the collector's docker_stats receiver, else are made up in the same style.
+		let builder = |group| metrics::builder(&meter, naming).disable(&disabled).group(group);
+
+		let meter_container_cpu_usage_seconds_total = builder("cpu")
+			.cadvisor("container_cpu_usage_seconds_total", "s", "Cumulative cpu time consumed")
+			// not emitted in semconv, as container.cpu.time is split by cpu.mode and this would double count in sums
+			.f64_counter();
+		let meter_container_cpu_user_seconds_total = builder("cpu")
+			.cadvisor("container_cpu_user_seconds_total", "s", "Cumulative userland cpu time consumed")
+			.semconv("container.cpu.time", "s", "Total CPU time consumed", [KeyValue::new("cpu.mode", "user")])
+			.f64_counter();
+		let meter_container_cpu_system_seconds_total = builder("cpu")
+			.cadvisor("container_cpu_system_seconds_total", "s", "Cumulative kernel cpu time consumed")
+			.semconv("container.cpu.time", "s", "Total CPU time consumed", [KeyValue::new("cpu.mode", "system")])
+			.f64_counter();
+
+		let meter_container_cpu_cfs_periods_total = builder("cfs")
+			.cadvisor("container_cpu_cfs_periods_total", "", "Number of elapsed enforcement period intervals")
+			.semconv(
+				"container.cpu.throttling_data.periods",
+				"{periods}",
+				"Number of elapsed enforcement period intervals",
+				[],
+			)
+			.u64_counter();
+		let meter_container_cpu_cfs_throttled_periods_total = builder("cfs")
+			.cadvisor("container_cpu_cfs_throttled_periods_total", "", "Number of throttled period intervals")
+			.semconv(
+				"container.cpu.throttling_data.throttled_periods",
+				"{periods}",
+				"Number of throttled period intervals",
+				[],
+			)
+			.u64_counter();
+		let meter_container_cpu_cfs_throttled_seconds_total = builder("cfs")
+			.cadvisor(
+				"container_cpu_cfs_throttled_seconds_total",
+				"s",
+				"Total time duration the container has been throttled",
+			)
+			.semconv(
+				"container.cpu.throttling_data.throttled_time",
+				"s",
+				"Total time duration the container has been throttled",
+				[],
+			)
+			.f64_counter();
+
+		let meter_container_cpu_utilization_ratio = builder("cpu")
+			.cadvisor("container_cpu_utilization_ratio", "1", "Share of the host's online cpus that the container used")
+			.semconv("container.cpu.utilization", "1", "Share of the host's online cpus that the container used", [])
+			.f64_gauge();
+		let meter_container_cpu_limit_utilization_ratio = builder("cpu")
+			.cadvisor("container_cpu_limit_utilization_ratio", "1", "Share of the container's cpu limit that it used")
+			.semconv("container.cpu.limit_utilization", "1", "Share of the container's cpu limit that it used", [])
+			.f64_gauge();
+
+		let meter_container_fs_reads_bytes_total = builder("fs")
+			.cadvisor("container_fs_reads_bytes_total", "By", "Cumulative bytes read")
+			.semconv(
+				"container.disk.io",
+				"By",
+				"Disk bytes for the container",
+				[KeyValue::new("disk.io.direction", "read")],
+			)
+			.u64_counter();
+		let meter_container_fs_writes_bytes_total = builder("fs")
+			.cadvisor("container_fs_writes_bytes_total", "By", "Cumulative bytes written")
+			.semconv(
+				"container.disk.io",
+				"By",
+				"Disk bytes for the container",
+				[KeyValue::new("disk.io.direction", "write")],
+			)
+			.u64_counter();
+
+		let meter_container_last_seen = builder("last_seen")
+			.cadvisor("container_last_seen", "", "Last time this container was seen by ContainerSpy")
+			.semconv("container.last_seen", "s", "Last time this container was seen by ContainerSpy", [])
+			.u64_gauge();
+
+		let meter_container_paused = builder("paused")
+			.cadvisor("container_paused", "", "Whether the container is paused")
+			.semconv("container.paused", "1", "Whether the container is paused", [])
+			.u64_gauge();
 
 		// annoyingly a lot of the meter names cadvisor went with don't have units attached even though they have known units
-		let meter_container_memory_cache = meter
-			.u64_gauge("container_memory_cache")
-			//.with_unit("By")
-			.with_description("Total page cache memory")
-			.build();
-		let meter_container_memory_failures_total = meter
-			.u64_counter("container_memory_failures_total")
-			.with_description("Cumulative count of memory allocation failures")
-			.build();
-		let meter_container_memory_mapped_file = meter
-			.u64_gauge("container_memory_mapped_file")
-			//.with_unit("By")
-			.with_description("Size of memory mapped files")
-			.build();
-		let meter_container_memory_rss = meter
-			.u64_gauge("container_memory_rss")
-			//.with_unit("By")
-			.with_description("Size of RSS")
-			.build();
-		let meter_container_memory_usage_bytes = meter
-			.u64_gauge("container_memory_usage_bytes")
-			.with_unit("By")
-			.with_description(
+		let meter_container_memory_cache = builder("memory")
+			.cadvisor("container_memory_cache", "", "Total page cache memory")
+			.semconv("container.memory.cache", "By", "Total page cache memory", [])
+			.u64_gauge();
+		let meter_container_memory_failures_min = builder("memory_failures")
+			.cadvisor("container_memory_failures_total", "", "Cumulative count of memory allocation failures")
+			.semconv("container.memory.pgfault", "{faults}", "Number of page faults", [])
+			.u64_counter();
+		let meter_container_memory_failures_maj = builder("memory_failures")
+			.cadvisor("container_memory_failures_total", "", "Cumulative count of memory allocation failures")
+			.semconv("container.memory.pgmajfault", "{faults}", "Number of major page faults", [])
+			.u64_counter();
+		let meter_container_memory_mapped_file = builder("memory")
+			.cadvisor("container_memory_mapped_file", "", "Size of memory mapped files")
+			.semconv("container.memory.mapped_file", "By", "Size of memory mapped files", [])
+			.u64_gauge();
+		let meter_container_memory_rss = builder("memory")
+			.cadvisor("container_memory_rss", "", "Size of RSS")
+			.semconv("container.memory.rss", "By", "Size of RSS", [])
+			.u64_gauge();
+		let meter_container_memory_usage_bytes = builder("memory")
+			.cadvisor(
+				"container_memory_usage_bytes",
+				"By",
 				"Current memory usage, including all memory regardless of when it was accessed",
 			)
-			.build();
-		let meter_container_memory_working_set_bytes = meter
-			.u64_gauge("container_memory_working_set_bytes")
-			.with_unit("By")
-			.with_description("Current working set")
-			.build();
-
-		let meter_container_network_receive_bytes_total = meter
-			.u64_counter("container_network_receive_bytes_total")
-			.with_unit("By")
-			.with_description("Cumulative count of bytes received")
-			.build();
+			.semconv("container.memory.usage", "By", "Memory usage of the container", [])
+			.u64_gauge();
+		let meter_container_memory_working_set_bytes = builder("memory")
+			.cadvisor("container_memory_working_set_bytes", "By", "Current working set")
+			.semconv("container.memory.working_set", "By", "Current working set", [])
+			.u64_gauge();
+		let meter_container_memory_utilization_ratio = builder("memory")
+			.cadvisor("container_memory_utilization_ratio", "1", "Working set as a share of the memory limit")
+			.semconv("container.memory.utilization", "1", "Working set as a share of the memory limit", [])
+			.f64_gauge();
+
+		let rx = || [KeyValue::new("network.io.direction", "receive")];
+		let tx = || [KeyValue::new("network.io.direction", "transmit")];
+
+		let meter_container_network_receive_bytes_total = builder("network")
+			.cadvisor("container_network_receive_bytes_total", "By", "Cumulative count of bytes received")
+			.semconv("container.network.io", "By", "Network bytes for the container", rx())
+			.u64_counter();
+		#[cfg(not(windows))]
+		let meter_container_network_receive_errors_total = builder("network")
+			.cadvisor(
+				"container_network_receive_errors_total",
+				"",
+				"Cumulative count of errors encountered while receiving",
+			)
+			.semconv("container.network.errors", "{errors}", "Network errors for the container", rx())
+			.u64_counter();
+		let meter_container_network_receive_packets_dropped_total = builder("network")
+			.cadvisor(
+				"container_network_receive_packets_dropped_total",
+				"",
+				"Cumulative count of packets dropped while receiving",
+			)
+			.semconv("container.network.dropped", "{packets}", "Network packets dropped by the container", rx())
+			.u64_counter();
+		let meter_container_network_receive_packets_total = builder("network")
+			.cadvisor("container_network_receive_packets_total", "", "Cumulative count of packets received")
+			.semconv("container.network.packets", "{packets}", "Network packets for the container", rx())
+			.u64_counter();
+
+		let meter_container_network_transmit_bytes_total = builder("network")
+			.cadvisor("container_network_transmit_bytes_total", "By", "Cumulative count of bytes transmitted")
+			.semconv("container.network.io", "By", "Network bytes for the container", tx())
+			.u64_counter();
+		#[cfg(not(windows))]
+		let meter_container_network_transmit_errors_total = builder("network")
+			.cadvisor(
+				"container_network_transmit_errors_total",
+				"",
+				"Cumulative count of errors encountered while transmitting",
+			)
+			.semconv("container.network.errors", "{errors}", "Network errors for the container", tx())
+			.u64_counter();
+		let meter_container_network_transmit_packets_dropped_total = builder("network")
+			.cadvisor(
+				"container_network_transmit_packets_dropped_total",
+				"",
+				"Cumulative count of packets dropped while transmitting",
+			)
+			.semconv("container.network.dropped", "{packets}", "Network packets dropped by the container", tx())
+			.u64_counter();
+		let meter_container_network_transmit_packets_total = builder("network")
+			.cadvisor("container_network_transmit_packets_total", "", "Cumulative count of packets transmitted")
+			.semconv("container.network.packets", "{packets}", "Network packets for the container", tx())
+			.u64_counter();
+
+		// per docker network totals, over all the containers on it
+		let meter_docker_network_receive_bytes_total = builder("network")
+			.cadvisor(
+				"docker_network_receive_bytes_total",
+				"By",
+				"Cumulative count of bytes received by containers on the network",
+			)
+			.semconv("docker.network.io", "By", "Network bytes for containers on the network", rx())
+			.u64_counter();
 		#[cfg(not(windows))]
-		let meter_container_network_receive_errors_total = meter
-			.u64_counter("container_network_receive_errors_total")
-			.with_description("Cumulative count of errors encountered while receiving")
-			.build();
-		let meter_container_network_receive_packets_dropped_total = meter
-			.u64_counter("container_network_receive_packets_dropped_total")
-			.with_description("Cumulative count of packets dropped while receiving")
-			.build();
-		let meter_container_network_receive_packets_total = meter
-			.u64_counter("container_network_receive_packets_total")
-			.with_description("Cumulative count of packets received")
-			.build();
-
-		let meter_container_network_transmit_bytes_total = meter
-			.u64_counter("container_network_transmit_bytes_total")
-			.with_unit("By")
-			.with_description("Cumulative count of bytes transmitted")
-			.build();
+		let meter_docker_network_receive_errors_total = builder("network")
+			.cadvisor(
+				"docker_network_receive_errors_total",
+				"",
+				"Cumulative count of errors encountered while receiving by containers on the network",
+			)
+			.semconv("docker.network.errors", "{errors}", "Network errors for containers on the network", rx())
+			.u64_counter();
+		let meter_docker_network_receive_packets_dropped_total = builder("network")
+			.cadvisor(
+				"docker_network_receive_packets_dropped_total",
+				"",
+				"Cumulative count of packets dropped while receiving by containers on the network",
+			)
+			.semconv("docker.network.dropped", "{packets}", "Network packets dropped by containers on the network", rx())
+			.u64_counter();
+		let meter_docker_network_receive_packets_total = builder("network")
+			.cadvisor(
+				"docker_network_receive_packets_total",
+				"",
+				"Cumulative count of packets received by containers on the network",
+			)
+			.semconv("docker.network.packets", "{packets}", "Network packets for containers on the network", rx())
+			.u64_counter();
+
+		let meter_docker_network_transmit_bytes_total = builder("network")
+			.cadvisor(
+				"docker_network_transmit_bytes_total",
+				"By",
+				"Cumulative count of bytes transmitted by containers on the network",
+			)
+			.semconv("docker.network.io", "By", "Network bytes for containers on the network", tx())
+			.u64_counter();
 		#[cfg(not(windows))]
-		let meter_container_network_transmit_errors_total = meter
-			.u64_counter("container_network_transmit_errors_total")
-			.with_description("Cumulative count of errors encountered while transmitting")
-			.build();
-		let meter_container_network_transmit_packets_dropped_total = meter
-			.u64_counter("container_network_transmit_packets_dropped_total")
-			.with_description("Cumulative count of packets dropped while transmitting")
-			.build();
-		let meter_container_network_transmit_packets_total = meter
-			.u64_counter("container_network_transmit_packets_total")
-			.with_description("Cumulative count of packets transmitted")
-			.build();
-
-		let meter_container_start_time_seconds = meter
-			.u64_gauge("container_start_time_seconds")
-			.with_unit("s")
-			.with_description("Start time of the container since unix epoch")
-			.build();
-
-		let meter_container_threads = meter
-			.u64_gauge("container_threads")
-			.with_description("Number of threads running inside the container")
-			.build();
-		let meter_container_threads_max = meter
-			.u64_gauge("container_threads_max")
-			.with_description("Maximum number of threads allowed inside the container")
-			.build();
+		let meter_docker_network_transmit_errors_total = builder("network")
+			.cadvisor(
+				"docker_network_transmit_errors_total",
+				"",
+				"Cumulative count of errors encountered while transmitting by containers on the network",
+			)
+			.semconv("docker.network.errors", "{errors}", "Network errors for containers on the network", tx())
+			.u64_counter();
+		let meter_docker_network_transmit_packets_dropped_total = builder("network")
+			.cadvisor(
+				"docker_network_transmit_packets_dropped_total",
+				"",
+				"Cumulative count of packets dropped while transmitting by containers on the network",
+			)
+			.semconv("docker.network.dropped", "{packets}", "Network packets dropped by containers on the network", tx())
+			.u64_counter();
+		let meter_docker_network_transmit_packets_total = builder("network")
+			.cadvisor(
+				"docker_network_transmit_packets_total",
+				"",
+				"Cumulative count of packets transmitted by containers on the network",
+			)
+			.semconv("docker.network.packets", "{packets}", "Network packets for containers on the network", tx())
+			.u64_counter();
+
+		// totals over groups of containers: a swarm service's tasks on this node, and a compose project or service
+		let swarm_service_totals = GroupMetrics {
+			cpu_usage: builder("swarm_service")
+				.cadvisor(
+					"swarm_service_cpu_usage_seconds_total",
+					"s",
+					"Cumulative cpu time consumed by the service's tasks",
+				)
+				.semconv("docker.swarm.service.cpu.time", "s", "Total CPU time consumed by the service's tasks", [])
+				.f64_counter(),
+			memory_usage: builder("swarm_service")
+				.cadvisor("swarm_service_memory_usage_bytes", "By", "Current memory usage of the service's tasks")
+				.semconv("docker.swarm.service.memory.usage", "By", "Memory usage of the service's tasks", [])
+				.u64_gauge(),
+			working_set: builder("swarm_service")
+				.cadvisor("swarm_service_memory_working_set_bytes", "By", "Current working set of the service's tasks")
+				.semconv("docker.swarm.service.memory.working_set", "By", "Current working set of the service's tasks", [])
+				.u64_gauge(),
+			containers: builder("swarm_service")
+				.cadvisor("swarm_service_tasks", "", "Number of the service's tasks running")
+				.semconv("docker.swarm.service.tasks", "{tasks}", "Number of the service's tasks running", [])
+				.u64_gauge(),
+		};
+		let compose_project_totals = GroupMetrics {
+			cpu_usage: builder("compose")
+				.cadvisor(
+					"compose_project_cpu_usage_seconds_total",
+					"s",
+					"Cumulative cpu time consumed by the project's containers",
+				)
+				.semconv("docker.compose.project.cpu.time", "s", "Total CPU time consumed by the project's containers", [])
+				.f64_counter(),
+			memory_usage: builder("compose")
+				.cadvisor("compose_project_memory_usage_bytes", "By", "Current memory usage of the project's containers")
+				.semconv("docker.compose.project.memory.usage", "By", "Memory usage of the project's containers", [])
+				.u64_gauge(),
+			working_set: builder("compose")
+				.cadvisor(
+					"compose_project_memory_working_set_bytes",
+					"By",
+					"Current working set of the project's containers",
+				)
+				.semconv(
+					"docker.compose.project.memory.working_set",
+					"By",
+					"Current working set of the project's containers",
+					[],
+				)
+				.u64_gauge(),
+			containers: builder("compose")
+				.cadvisor("compose_project_containers", "", "Number of the project's containers running")
+				.semconv(
+					"docker.compose.project.containers",
+					"{containers}",
+					"Number of the project's containers running",
+					[],
+				)
+				.u64_gauge(),
+		};
+		let compose_service_totals = GroupMetrics {
+			cpu_usage: builder("compose")
+				.cadvisor(
+					"compose_service_cpu_usage_seconds_total",
+					"s",
+					"Cumulative cpu time consumed by the service's containers",
+				)
+				.semconv("docker.compose.service.cpu.time", "s", "Total CPU time consumed by the service's containers", [])
+				.f64_counter(),
+			memory_usage: builder("compose")
+				.cadvisor("compose_service_memory_usage_bytes", "By", "Current memory usage of the service's containers")
+				.semconv("docker.compose.service.memory.usage", "By", "Memory usage of the service's containers", [])
+				.u64_gauge(),
+			working_set: builder("compose")
+				.cadvisor(
+					"compose_service_memory_working_set_bytes",
+					"By",
+					"Current working set of the service's containers",
+				)
+				.semconv(
+					"docker.compose.service.memory.working_set",
+					"By",
+					"Current working set of the service's containers",
+					[],
+				)
+				.u64_gauge(),
+			containers: builder("compose")
+				.cadvisor("compose_service_containers", "", "Number of the service's containers running")
+				.semconv(
+					"docker.compose.service.containers",
+					"{containers}",
+					"Number of the service's containers running",
+					[],
+				)
+				.u64_gauge(),
+		};
+
+		// this container counts towards its groups' totals while this worker runs
+		let host = conn.host.as_deref();
+		let groups: Vec<Member> = [
+			swarm_task.map(|t| Member::join(t.service_labels(host), &swarm_service_totals)),
+			compose_container.as_ref().map(|c| Member::join(c.project_labels(host), &compose_project_totals)),
+			compose_container
+				.as_ref()
+				.and_then(|c| c.service_labels(host))
+				.map(|l| Member::join(l, &compose_service_totals)),
+		]
+		.into_iter()
+		.flatten()
+		.collect();
+
+		let meter_container_start_time_seconds = builder("start_time")
+			.cadvisor("container_start_time_seconds", "s", "Start time of the container since unix epoch")
+			.u64_gauge();
+		// semconv reports uptime rather than start time
+		let meter_container_uptime = builder("start_time")
+			.semconv("container.uptime", "s", "The time the container has been running", [])
+			.u64_gauge();
+
+		let meter_container_threads = builder("threads")
+			.cadvisor("container_threads", "", "Number of threads running inside the container")
+			.semconv("container.pids.count", "{pids}", "Number of threads running inside the container", [])
+			.u64_gauge();
+		let meter_container_threads_max = builder("threads")
+			.cadvisor("container_threads_max", "", "Maximum number of threads allowed inside the container")
+			.semconv("container.pids.limit", "{pids}", "Maximum number of threads allowed inside the container", [])
+			.u64_gauge();
+
+		let mut last_paused = *paused_rx.borrow();
 
 		while let Some(val) = stats_stream.next().await {
 			if let Ok(stats) = val {
-				// when a container exits, instead of a None we get sent Ok()s with zeroes in it forever, horror
-				if stats.cpu_stats.cpu_usage.total_usage == 0 {
+				// the watcher tells us when the container is paused, as of its last list of containers
+				let mut paused = *paused_rx.borrow();
+
+				// when a container exits, instead of a None we get sent Ok()s with zeroes in it forever, horror.
+				// some engines send zeroes for paused containers too, so make sure it wasn't just paused before giving up
+				if stats.cpu_stats.cpu_usage.total_usage == 0 && !paused {
 					if stats.precpu_stats.cpu_usage.total_usage != 0 {
-						break;
+						if !is_paused(docker, &container_id).await {
+							break;
+						}
+						paused = true;
 					} else {
 						// last time was ALSO a zero, so this MIGHT actually be (SOMEHOW?) legit,
 						// so just loop around again, and wait for the main task to abort() this worker task instead!
@@ -255,52 +557,76 @@ pub fn launch_stats_task(
 					}
 				};
 
-				meter_container_cpu_usage_seconds_total.add(
-					cpu_delta_from_docker(
+				health::container_sampled(&container_id);
+
+				if paused != last_paused {
+					let state = if paused { "paused" } else { "unpaused" };
+					debug(format_args!("Container {container_id} was {state}"), [("container_id", &*container_id)]);
+					last_paused = paused;
+				}
+
+				// a paused container uses no cpu. the first read after unpausing can also be diffed against zeroes from
+				// while it was paused, which would count all of its cpu time again
+				if !paused && stats.precpu_stats.cpu_usage.total_usage != 0 {
+					let cpu_usage = cpu_delta_from_docker(
 						stats.cpu_stats.cpu_usage.total_usage,
 						stats.precpu_stats.cpu_usage.total_usage,
 					)
-					.as_secs_f64(),
-					shared_labels,
-				);
-
-				meter_container_cpu_user_seconds_total.add(
-					cpu_delta_from_docker(
-						stats.cpu_stats.cpu_usage.usage_in_usermode,
-						stats.precpu_stats.cpu_usage.usage_in_usermode,
-					)
-					.as_secs_f64(),
-					shared_labels,
-				);
-
-				meter_container_cpu_system_seconds_total.add(
-					cpu_delta_from_docker(
-						stats.cpu_stats.cpu_usage.usage_in_kernelmode,
-						stats.precpu_stats.cpu_usage.usage_in_kernelmode,
-					)
-					.as_secs_f64(),
-					shared_labels,
-				);
-
-				meter_container_cpu_cfs_periods_total.add(
-					stats.cpu_stats.throttling_data.periods - stats.precpu_stats.throttling_data.periods,
-					shared_labels,
-				);
-
-				meter_container_cpu_cfs_throttled_periods_total.add(
-					stats.cpu_stats.throttling_data.throttled_periods
-						- stats.precpu_stats.throttling_data.throttled_periods,
-					shared_labels,
-				);
-
-				meter_container_cpu_cfs_throttled_seconds_total.add(
-					cpu_delta_from_docker(
-						stats.cpu_stats.throttling_data.throttled_time,
-						stats.precpu_stats.throttling_data.throttled_time,
-					)
-					.as_secs_f64(),
-					shared_labels,
-				);
+					.as_secs_f64();
+
+					meter_container_cpu_usage_seconds_total.record(cpu_usage, shared_labels);
+
+					for group in &groups {
+						group.record_cpu(cpu_usage);
+					}
+
+					meter_container_cpu_user_seconds_total.record(
+						cpu_delta_from_docker(
+							stats.cpu_stats.cpu_usage.usage_in_usermode,
+							stats.precpu_stats.cpu_usage.usage_in_usermode,
+						)
+						.as_secs_f64(),
+						shared_labels,
+					);
+
+					meter_container_cpu_system_seconds_total.record(
+						cpu_delta_from_docker(
+							stats.cpu_stats.cpu_usage.usage_in_kernelmode,
+							stats.precpu_stats.cpu_usage.usage_in_kernelmode,
+						)
+						.as_secs_f64(),
+						shared_labels,
+					);
+
+					meter_container_cpu_cfs_periods_total.record(
+						stats.cpu_stats.throttling_data.periods - stats.precpu_stats.throttling_data.periods,
+						shared_labels,
+					);
+
+					meter_container_cpu_cfs_throttled_periods_total.record(
+						stats.cpu_stats.throttling_data.throttled_periods
+							- stats.precpu_stats.throttling_data.throttled_periods,
+						shared_labels,
+					);
+
+					meter_container_cpu_cfs_throttled_seconds_total.record(
+						cpu_delta_from_docker(
+							stats.cpu_stats.throttling_data.throttled_time,
+							stats.precpu_stats.throttling_data.throttled_time,
+						)
+						.as_secs_f64(),
+						shared_labels,
+					);
+
+					if utilization {
+						if let Some((online, limit)) = cpu_utilization(&stats.cpu_stats, &stats.precpu_stats, cpu_limit) {
+							meter_container_cpu_utilization_ratio.record(online, shared_labels);
+							if let Some(limit) = limit {
+								meter_container_cpu_limit_utilization_ratio.record(limit, shared_labels);
+							}
+						}
+					}
+				}
 
 				// other blkio_stats values only exist on cgroups v1 so don't bother.
 				// io_service_bytes_recursive exists only on cgroups v1.
@@ -311,21 +637,21 @@ pub fn launch_stats_task(
 						let (last_r, last_w) = get_rw_totals(last);
 						let (curr_r, curr_w) = get_rw_totals(&service_bytes_rec);
 
-						meter_container_fs_reads_bytes_total.add(curr_r - last_r, shared_labels);
-						meter_container_fs_writes_bytes_total.add(curr_w - last_w, shared_labels);
+						meter_container_fs_reads_bytes_total.record(curr_r - last_r, shared_labels);
+						meter_container_fs_writes_bytes_total.record(curr_w - last_w, shared_labels);
 					}
 
 					last_io_stats = Some(service_bytes_rec);
 				}
 				// TODO: handle windows storage stats
 
-				meter_container_last_seen.record(
-					SystemTime::now()
-						.duration_since(UNIX_EPOCH)
-						.unwrap()
-						.as_secs(),
-					shared_labels,
-				);
+				let now_secs = SystemTime::now()
+					.duration_since(UNIX_EPOCH)
+					.unwrap()
+					.as_secs();
+
+				meter_container_last_seen.record(now_secs, shared_labels);
+				meter_container_paused.record(paused as u64, shared_labels);
 
 				// cgroups values references:
 				// - https://github.com/docker/cli/blob/91cbde67/cli/command/container/stats_helpers.go#L230-L231
@@ -348,20 +674,20 @@ pub fn launch_stats_task(
 						// container_memory_failures_total
 						// need last
 						if let Some(MemoryStatsStats::V2(last_v2)) = last_mem_stats.stats {
-							meter_container_memory_failures_total.add(
+							meter_container_memory_failures_min.record(
 								v2stats.pgfault - last_v2.pgfault,
 								labels_mem_container_min_c,
 							);
-							meter_container_memory_failures_total.add(
+							meter_container_memory_failures_min.record_cadvisor_only(
 								v2stats.pgfault - last_v2.pgfault,
 								labels_mem_container_min_h,
 							);
 
-							meter_container_memory_failures_total.add(
+							meter_container_memory_failures_maj.record(
 								v2stats.pgmajfault - last_v2.pgmajfault,
 								labels_mem_container_maj_c,
 							);
-							meter_container_memory_failures_total.add(
+							meter_container_memory_failures_maj.record_cadvisor_only(
 								v2stats.pgmajfault - last_v2.pgmajfault,
 								labels_mem_container_maj_h,
 							);
@@ -386,8 +712,27 @@ pub fn launch_stats_task(
 						meter_container_memory_usage_bytes.record(all_usage, shared_labels);
 
 						// container_memory_working_set_bytes
-						meter_container_memory_working_set_bytes
-							.record(all_usage - v2stats.inactive_file, shared_labels);
+						let working_set = all_usage - v2stats.inactive_file;
+						meter_container_memory_working_set_bytes.record(working_set, shared_labels);
+						for group in &groups {
+							group.record_memory(all_usage, working_set);
+						}
+
+						// docker reports the host's memory as the limit when there isn't one
+						if let Some(limit) = stats.memory_stats.limit.filter(|l| utilization && *l > 0) {
+							meter_container_memory_utilization_ratio.record(working_set as f64 / limit as f64, shared_labels);
+						}
+					} else if stats.memory_stats.stats.is_none() {
+						// podman's docker-compatible API only has the usage, which is the closest we have to a working set
+						meter_container_memory_usage_bytes.record(all_usage, shared_labels);
+						meter_container_memory_working_set_bytes.record(all_usage, shared_labels);
+						for group in &groups {
+							group.record_memory(all_usage, all_usage);
+						}
+
+						if let Some(limit) = stats.memory_stats.limit.filter(|l| utilization && *l > 0) {
+							meter_container_memory_utilization_ratio.record(all_usage as f64 / limit as f64, shared_labels);
+						}
 					}
 				}
 
@@ -395,43 +740,98 @@ pub fn launch_stats_task(
 
 				// networking
 				// TODO: what is stats.network? is it populated on windows?
-				if let Some(net) = &stats.networks {
+				if let Some(net) = stats.networks.as_ref().filter(|_| !shared_netns) {
+					let mut current: Vec<&str> = net.keys().map(String::as_str).collect();
+					current.sort_unstable();
+
+					if current != interfaces {
+						let fresh;
+						let details = match startup_details.take() {
+							Some(d) => Some(d),
+							None => {
+								fresh = labels::inspect(docker, &container_id).await;
+								fresh.as_ref()
+							}
+						};
+
+						let networks = match details {
+							Some(d) => networks::map_interfaces(docker, d, &current, host_proc.as_deref()).await,
+							None => HashMap::new(),
+						};
+
+						interface_labels = current
+							.iter()
+							.map(|iface| {
+								let mut labels = shared_labels.with("interface", "network.interface.name", iface.to_string());
+								labels.push("network_mode", "docker.network.mode", network_mode.clone());
+								let mut network = networks.get(*iface).map(networks::Network::labels);
+								if let Some(network) = &network {
+									labels.extend(network.clone());
+								}
+								// the per-network series don't have the container's labels, which already include the host
+								if let (Some(network), Some(host)) = (&mut network, &conn.host) {
+									network.push("docker_host", "docker.host", host.clone());
+								}
+								(iface.to_string(), (labels, network))
+							})
+							.collect();
+						interfaces = current.into_iter().map(str::to_owned).collect();
+					}
+
 					if let Some(last_net_stats) = &last_net_stats {
 						for (interface, this_inter) in net {
 							// try to get last
-							if let Some(last_this_inter) = last_net_stats.get(interface) {
-								// net labels
-								let mut net_labels = Vec::with_capacity(shared_labels.len() + 1);
-								net_labels.extend_from_slice(shared_labels);
-								net_labels.push(KeyValue::new("interface", interface.clone()));
-								let net_labels = &net_labels.into_boxed_slice()[..];
+							if let (Some(last_this_inter), Some((net_labels, network))) =
+								(last_net_stats.get(interface), interface_labels.get(interface))
+							{
 
 								meter_container_network_receive_bytes_total
-									.add(this_inter.rx_bytes - last_this_inter.rx_bytes, net_labels);
+									.record(this_inter.rx_bytes - last_this_inter.rx_bytes, net_labels);
 								meter_container_network_transmit_bytes_total
-									.add(this_inter.tx_bytes - last_this_inter.tx_bytes, net_labels);
+									.record(this_inter.tx_bytes - last_this_inter.tx_bytes, net_labels);
 								#[cfg(not(windows))]
 								meter_container_network_receive_errors_total
-									.add(this_inter.rx_errors - last_this_inter.rx_errors, net_labels);
+									.record(this_inter.rx_errors - last_this_inter.rx_errors, net_labels);
 								#[cfg(not(windows))]
 								meter_container_network_transmit_errors_total
-									.add(this_inter.tx_errors - last_this_inter.tx_errors, net_labels);
-								meter_container_network_receive_packets_dropped_total.add(
+									.record(this_inter.tx_errors - last_this_inter.tx_errors, net_labels);
+								meter_container_network_receive_packets_dropped_total.record(
 									this_inter.rx_dropped - last_this_inter.rx_dropped,
 									net_labels,
 								);
-								meter_container_network_transmit_packets_dropped_total.add(
+								meter_container_network_transmit_packets_dropped_total.record(
 									this_inter.tx_dropped - last_this_inter.tx_dropped,
 									net_labels,
 								);
-								meter_container_network_receive_packets_total.add(
+								meter_container_network_receive_packets_total.record(
 									this_inter.rx_packets - last_this_inter.rx_packets,
 									net_labels,
 								);
-								meter_container_network_transmit_packets_total.add(
+								meter_container_network_transmit_packets_total.record(
 									this_inter.tx_packets - last_this_inter.tx_packets,
 									net_labels,
 								);
+
+								if let Some(network) = network {
+									meter_docker_network_receive_bytes_total
+										.record(this_inter.rx_bytes - last_this_inter.rx_bytes, network);
+									meter_docker_network_transmit_bytes_total
+										.record(this_inter.tx_bytes - last_this_inter.tx_bytes, network);
+									#[cfg(not(windows))]
+									meter_docker_network_receive_errors_total
+										.record(this_inter.rx_errors - last_this_inter.rx_errors, network);
+									#[cfg(not(windows))]
+									meter_docker_network_transmit_errors_total
+										.record(this_inter.tx_errors - last_this_inter.tx_errors, network);
+									meter_docker_network_receive_packets_dropped_total
+										.record(this_inter.rx_dropped - last_this_inter.rx_dropped, network);
+									meter_docker_network_transmit_packets_dropped_total
+										.record(this_inter.tx_dropped - last_this_inter.tx_dropped, network);
+									meter_docker_network_receive_packets_total
+										.record(this_inter.rx_packets - last_this_inter.rx_packets, network);
+									meter_docker_network_transmit_packets_total
+										.record(this_inter.tx_packets - last_this_inter.tx_packets, network);
+								}
 							}
 						}
 					}
@@ -450,12 +850,18 @@ pub fn launch_stats_task(
 				if let Some(Ok(secs)) = container.created.map(u64::try_from) {
 					//let date = UNIX_EPOCH + Duration::from_nanos(secs);
 					meter_container_start_time_seconds.record(secs, shared_labels);
+					meter_container_uptime.record(now_secs.saturating_sub(secs), shared_labels);
 				}
 			} else {
 				// failed to get stats, log as such:
-				error(format_args!("Failed to get stats for container {container_id}!: {:?}", val.unwrap_err()), [("container_id", &*container_id)]);
+				let err = val.unwrap_err();
+				self_metrics::stream_error(&container_id);
+				health::container_error(&container_id, err.to_string());
+				error(format_args!("Failed to get stats for container {container_id}!: {err:?}"), [("container_id", &*container_id)]);
 			}
 		}
+
+		self_metrics::worker_terminated("stream_ended", 1);
 	})
 }
 
@@ -469,6 +875,50 @@ fn cpu_delta_from_docker(cpu_usage: u64, precpu_usage: u64) -> Duration {
 	Duration::from_nanos(delta_ns)
 }
 
+/// whether the container is paused right now, for when its stats look like it has exited
+async fn is_paused(docker: &bollard::Docker, container_id: &str) -> bool {
+	// a container that has exited may be gone already, which isn't worth a warning
+	self_metrics::time_docker("inspect_container", docker.inspect_container(container_id, None))
+		.await
+		.ok()
+		.and_then(|d| d.state)
+		.and_then(|s| s.paused)
+		.unwrap_or(false)
+}
+
+/// the number of cpus a container is limited to, from --cpus or --cpu-quota, if it has a limit
+fn cpu_limit(details: &ContainerInspectResponse) -> Option<f64> {
+	let host_config = details.host_config.as_ref()?;
+	match (host_config.nano_cpus, host_config.cpu_quota, host_config.cpu_period) {
+		(Some(nano), _, _) if nano > 0 => Some(nano as f64 / 1e9),
+		// the kernel's default period is 100ms, which docker leaves as 0
+		(_, Some(quota), period) if quota > 0 => Some(quota as f64 / period.filter(|p| *p > 0).unwrap_or(100_000) as f64),
+		_ => None,
+	}
+}
+
+/// cpu used since the last read, as a share of the online cpus, and of the limit if there is one, or of the online
+/// cpus if the limit is higher. the same calculation as `docker stats`, but scaled to 0-1 rather than 0-100% per cpu
+fn cpu_utilization(cpu: &CPUStats, precpu: &CPUStats, cpu_limit: Option<f64>) -> Option<(f64, Option<f64>)> {
+	// windows doesn't report system usage, and the first read has no previous system usage to diff against
+	let system = cpu.system_cpu_usage?;
+	let pre_system = precpu.system_cpu_usage.filter(|s| *s > 0)?;
+	let system_delta = system.checked_sub(pre_system).filter(|d| *d > 0)?;
+
+	let cpu_delta = cpu.cpu_usage.total_usage.saturating_sub(precpu.cpu_usage.total_usage);
+
+	// cgroup v2 reports an empty percpu_usage, which would divide by zero
+	let online = match cpu.online_cpus {
+		Some(n) if n > 0 => n,
+		_ => cpu.cpu_usage.percpu_usage.as_ref().map(|p| p.len() as u64).filter(|n| *n > 0)?,
+	} as f64;
+
+	// system usage is summed over every cpu, so this is the number of cpus used
+	let used = cpu_delta as f64 / system_delta as f64 * online;
+
+	Some((used / online, cpu_limit.map(|l| used / l.min(online))))
+}
+
 fn get_rw_totals<'a>(iter: impl IntoIterator<Item = &'a BlkioStatsEntry>) -> (u64, u64) {
 	let mut read = 0;
 	let mut write = 0;
@@ -477,9 +927,81 @@ fn get_rw_totals<'a>(iter: impl IntoIterator<Item = &'a BlkioStatsEntry>) -> (u6
 		match entry.op.as_str() {
 			"read" => read += entry.value,
 			"write" => write += entry.value,
-			_ => println!("Unknown service_bytes_recursive entry type {}", entry.op),
+			op => debug(format_args!("Unknown service_bytes_recursive entry type {op}"), [("op", op)]),
 		}
 	}
 
 	(read, write)
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bollard::container::{CPUUsage, ThrottlingData};
+	use bollard::models::HostConfig;
+
+	fn cpu_stats(total_usage: u64, system: u64, online_cpus: u64) -> CPUStats {
+		CPUStats {
+			cpu_usage: CPUUsage {
+				percpu_usage: None,
+				usage_in_usermode: 0,
+				total_usage,
+				usage_in_kernelmode: 0,
+			},
+			system_cpu_usage: Some(system),
+			online_cpus: Some(online_cpus),
+			throttling_data: ThrottlingData { periods: 0, throttled_periods: 0, throttled_time: 0 },
+		}
+	}
+
+	fn limit_of(nano_cpus: Option<i64>, cpu_quota: Option<i64>, cpu_period: Option<i64>) -> Option<f64> {
+		cpu_limit(&ContainerInspectResponse {
+			host_config: Some(HostConfig { nano_cpus, cpu_quota, cpu_period, ..Default::default() }),
+			..Default::default()
+		})
+	}
+
+	#[test]
+	fn reads_cpu_limits() {
+		// --cpus 1.5
+		assert_eq!(limit_of(Some(1_500_000_000), Some(0), Some(0)), Some(1.5));
+		// --cpu-quota 50000, with and without --cpu-period
+		assert_eq!(limit_of(Some(0), Some(50_000), Some(0)), Some(0.5));
+		assert_eq!(limit_of(None, Some(50_000), Some(25_000)), Some(2.0));
+		// no limit
+		assert_eq!(limit_of(Some(0), Some(0), Some(0)), None);
+		assert_eq!(limit_of(None, Some(-1), None), None);
+		assert_eq!(cpu_limit(&ContainerInspectResponse::default()), None);
+	}
+
+	#[test]
+	fn calculates_cpu_utilization() {
+		// 4 cpus, so 4s of system time per second, of which the container used 1s
+		let pre = cpu_stats(1_000_000_000, 10_000_000_000, 4);
+		let cpu = cpu_stats(2_000_000_000, 14_000_000_000, 4);
+
+		assert_eq!(cpu_utilization(&cpu, &pre, None), Some((0.25, None)));
+		assert_eq!(cpu_utilization(&cpu, &pre, Some(2.0)), Some((0.25, Some(0.5))));
+		// a limit above the online cpus can't be reached, so the online cpus are used instead
+		assert_eq!(cpu_utilization(&cpu, &pre, Some(8.0)), Some((0.25, Some(0.25))));
+
+		// online_cpus is missing on older engines, so the per-cpu usage is counted instead
+		let mut old = cpu.clone();
+		old.online_cpus = None;
+		old.cpu_usage.percpu_usage = Some(vec![0; 2]);
+		assert_eq!(cpu_utilization(&old, &pre, None), Some((0.25, None)));
+
+		// without either, there's nothing to divide by
+		old.cpu_usage.percpu_usage = Some(vec![]);
+		assert_eq!(cpu_utilization(&old, &pre, Some(2.0)), None);
+		old.cpu_usage.percpu_usage = None;
+		assert_eq!(cpu_utilization(&old, &pre, None), None);
+
+		// the first read has no previous system usage, and windows reports none at all
+		let first = cpu_stats(0, 0, 0);
+		assert_eq!(cpu_utilization(&cpu, &first, None), None);
+		let mut windows = cpu.clone();
+		windows.system_cpu_usage = None;
+		assert_eq!(cpu_utilization(&windows, &pre, None), None);
+	}
+}
@@ -0,0 +1,98 @@
+// labels for containers that are docker swarm tasks
+
+use std::collections::HashMap;
+
+use crate::metrics::Labels;
+
+const SERVICE_NAME: &str = "com.docker.swarm.service.name";
+const TASK_NAME: &str = "com.docker.swarm.task.name";
+const NODE_ID: &str = "com.docker.swarm.node.id";
+
+/// the swarm task a container runs, from the labels swarm puts on its containers
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Task {
+	pub service: String,
+	/// only replicated services have slots, global services have one task per node instead
+	pub slot: Option<String>,
+	pub node: Option<String>,
+}
+
+impl Task {
+	pub fn from_labels(labels: &HashMap<String, String>) -> Option<Task> {
+		let service = labels.get(SERVICE_NAME)?;
+
+		// task names are <service>.<slot>.<task id>, or <service>.<node id>.<task id> for global services
+		let slot = labels
+			.get(TASK_NAME)
+			.and_then(|n| n.strip_prefix(service.as_str())?.strip_prefix('.')?.split('.').next())
+			.filter(|s| s.parse::<u64>().is_ok())
+			.map(str::to_owned);
+
+		Some(Task {
+			service: service.clone(),
+			slot,
+			node: labels.get(NODE_ID).cloned(),
+		})
+	}
+
+	/// the attributes identifying this task
+	pub fn labels(&self) -> Labels {
+		let mut out = Labels::default();
+		out.push("service", "docker.swarm.service.name", self.service.clone());
+		if let Some(slot) = &self.slot {
+			out.push("task_slot", "docker.swarm.task.slot", slot.clone());
+		}
+		if let Some(node) = &self.node {
+			out.push("node", "docker.swarm.node.id", node.clone());
+		}
+		out
+	}
+
+	/// the attributes for the per-service totals, which sum over the service's tasks on this node
+	pub fn service_labels(&self, host: Option<&str>) -> Labels {
+		let mut out = Labels::default();
+		out.push("service", "docker.swarm.service.name", self.service.clone());
+		if let Some(node) = &self.node {
+			out.push("node", "docker.swarm.node.id", node.clone());
+		}
+		if let Some(host) = host {
+			out.push("docker_host", "docker.host", host.to_owned());
+		}
+		out
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn labels(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+		pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+	}
+
+	#[test]
+	fn parses_tasks() {
+		let replicated = labels(&[
+			(SERVICE_NAME, "web.api"),
+			(TASK_NAME, "web.api.3.q8wn3ex1y2rgjzjm2cbfeoxv9"),
+			(NODE_ID, "tq3ybgbbuvr1v6l2xpl1q2ydw"),
+		]);
+		assert_eq!(
+			Task::from_labels(&replicated),
+			Some(Task {
+				service: "web.api".to_owned(),
+				slot: Some("3".to_owned()),
+				node: Some("tq3ybgbbuvr1v6l2xpl1q2ydw".to_owned()),
+			})
+		);
+
+		let global = labels(&[
+			(SERVICE_NAME, "cspy"),
+			(TASK_NAME, "cspy.tq3ybgbbuvr1v6l2xpl1q2ydw.m4y3v1k1cw5x0v1wqh4e3hq1z"),
+			(NODE_ID, "tq3ybgbbuvr1v6l2xpl1q2ydw"),
+		]);
+		assert_eq!(Task::from_labels(&global).unwrap().slot, None);
+
+		assert_eq!(Task::from_labels(&labels(&[("com.docker.compose.service", "web")])), None);
+	}
+}
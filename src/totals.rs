@@ -0,0 +1,90 @@
+// cpu and memory totals over groups of containers, like a swarm service's tasks or a compose project's containers
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use opentelemetry::metrics::{Counter, Gauge};
+use opentelemetry::KeyValue;
+
+use crate::metrics::{Labels, Metric};
+
+/// the metrics for one kind of group
+pub struct GroupMetrics {
+	pub cpu_usage: Metric<Counter<f64>>,
+	pub memory_usage: Metric<Gauge<u64>>,
+	pub working_set: Metric<Gauge<u64>>,
+	/// the number of containers in the group
+	pub containers: Metric<Gauge<u64>>,
+}
+
+struct Usage {
+	/// the group, identified by its attributes
+	group: Vec<KeyValue>,
+	memory_usage: u64,
+	working_set: u64,
+}
+
+/// the latest usage of every member, by member id rather than container id, as a restarted worker can join before the
+/// one it replaces has been dropped
+static MEMBERS: Mutex<BTreeMap<u64, Usage>> = Mutex::new(BTreeMap::new());
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// a container's part in a group's totals, which it leaves when dropped, e.g. when its worker is aborted
+pub struct Member<'a> {
+	id: u64,
+	labels: Labels,
+	metrics: &'a GroupMetrics,
+}
+
+impl<'a> Member<'a> {
+	/// joins the group with these attributes, which are also the attributes of its metrics
+	pub fn join(labels: Labels, metrics: &'a GroupMetrics) -> Member<'a> {
+		let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+		MEMBERS.lock().unwrap().insert(
+			id,
+			Usage {
+				group: labels.cadvisor.clone(),
+				memory_usage: 0,
+				working_set: 0,
+			},
+		);
+
+		Member { id, labels, metrics }
+	}
+
+	/// adds cpu time used by this container since the last read to the group's total
+	pub fn record_cpu(&self, seconds: f64) {
+		self.metrics.cpu_usage.record(seconds, &self.labels);
+	}
+
+	/// updates this container's memory usage, and records the group's new totals
+	pub fn record_memory(&self, memory_usage: u64, working_set: u64) {
+		let mut members = MEMBERS.lock().unwrap();
+
+		let Some(this) = members.get_mut(&self.id) else {
+			return;
+		};
+		this.memory_usage = memory_usage;
+		this.working_set = working_set;
+		let group = this.group.clone();
+
+		let (mut memory_usage, mut working_set, mut containers) = (0, 0, 0);
+		for member in members.values().filter(|m| m.group == group) {
+			memory_usage += member.memory_usage;
+			working_set += member.working_set;
+			containers += 1;
+		}
+		drop(members);
+
+		self.metrics.memory_usage.record(memory_usage, &self.labels);
+		self.metrics.working_set.record(working_set, &self.labels);
+		self.metrics.containers.record(containers, &self.labels);
+	}
+}
+
+impl Drop for Member<'_> {
+	fn drop(&mut self) {
+		MEMBERS.lock().unwrap().remove(&self.id);
+	}
+}
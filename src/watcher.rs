@@ -0,0 +1,351 @@
+// the discovery loop for one docker engine, which launches and kills the workers for its containers
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use opentelemetry::logs::LoggerProvider;
+use opentelemetry::metrics::Meter;
+use opentelemetry_sdk::logs::SdkLoggerProvider;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::MissedTickBehavior;
+use tokio_util::sync::CancellationToken;
+
+use crate::docker_host::Client;
+use crate::engine::{self, Connection};
+use crate::s_log::*;
+use crate::{config, events_task, health, labels, logs_task, self_metrics, stats_task};
+
+/// what every watcher needs from the main loop, which changes on reload
+#[derive(Clone)]
+pub struct Shared {
+	pub meter: Arc<Meter>,
+	pub logger_provider: Option<SdkLoggerProvider>,
+	/// how often to list containers
+	pub interval: Duration,
+	/// bumped to restart all workers, just the log workers, or just the events worker
+	pub workers_gen: u64,
+	pub log_workers_gen: u64,
+	pub events_gen: u64,
+}
+
+pub struct Watcher {
+	/// the address and timeout this watcher was started with, to restart it if they change
+	pub address: Option<String>,
+	pub timeout: u64,
+	cancel: CancellationToken,
+	handle: JoinHandle<()>,
+}
+
+impl Watcher {
+	/// starts watching the engine `client` is connected to, labelling everything with `host` if set
+	pub fn spawn(
+		client: Client,
+		address: Option<String>,
+		host: Option<String>,
+		shared: watch::Receiver<Shared>,
+	) -> Watcher {
+		let timeout = client.docker.timeout().as_secs();
+		let cancel = CancellationToken::new();
+		let handle = tokio::spawn(run(client, address.clone(), host, shared, cancel.clone()));
+
+		Watcher { address, timeout, cancel, handle }
+	}
+
+	/// stops all of this watcher's workers, and waits for it to finish
+	pub async fn shutdown(self) {
+		self.cancel.cancel();
+		let _ = self.handle.await;
+	}
+}
+
+fn search_interval(period: Duration) -> tokio::time::Interval {
+	let mut interval = tokio::time::interval(period);
+	interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+	interval
+}
+
+/// starts exporting docker events, if enabled
+fn spawn_events(conn: &Arc<Connection>, shared: &Shared) -> Option<JoinHandle<()>> {
+	let logger = shared.logger_provider.as_ref().map(|p| Arc::new(p.logger("cspy_events")));
+
+	config::current()
+		.container_events
+		.then(|| events_task::launch_events_task(conn.clone(), shared.meter.clone(), logger))
+}
+
+/// a container's stats worker, and how to tell it when the container is paused or unpaused
+struct Worker {
+	handle: JoinHandle<()>,
+	paused: watch::Sender<bool>,
+}
+
+fn running_workers(tasks: &BTreeMap<String, Worker>) -> usize {
+	tasks.values().filter(|w| !w.handle.is_finished()).count()
+}
+
+async fn run(
+	mut client: Client,
+	address: Option<String>,
+	host: Option<String>,
+	mut shared_rx: watch::Receiver<Shared>,
+	cancel: CancellationToken,
+) {
+	// for logs and health checks
+	let name = address.unwrap_or_else(|| "default".to_owned());
+
+	let mut shared = shared_rx.borrow_and_update().clone();
+	let mut container_search_interval = search_interval(shared.interval);
+
+	// only set up once we know which engine we're talking to, so after the first successful list
+	let mut conn: Option<Arc<Connection>> = None;
+	let mut detected = false;
+
+	let mut tasks: BTreeMap<String, Worker> = BTreeMap::new();
+	// log forwarding workers, separate from stats workers as not every container has one
+	let mut log_tasks: BTreeMap<String, JoinHandle<()>> = BTreeMap::new();
+	// each container's inspect response, shared by its stats and log workers
+	let mut inspections: BTreeMap<String, labels::SharedInspect> = BTreeMap::new();
+	let mut events: Option<JoinHandle<()>> = None;
+
+	// count as alive while the first list_containers call is in progress
+	health::tick(&name, container_search_interval.period());
+
+	loop {
+		tokio::select! {
+			_ = container_search_interval.tick() => {}
+			changed = shared_rx.changed() => {
+				if changed.is_err() {
+					break;
+				}
+
+				let new = shared_rx.borrow_and_update().clone();
+				if new.interval != shared.interval {
+					container_search_interval = search_interval(new.interval);
+				}
+
+				let restart_workers = new.workers_gen != shared.workers_gen;
+
+				if restart_workers || new.events_gen != shared.events_gen {
+					if let Some(task) = events.take() {
+						task.abort();
+					}
+					events = conn.as_ref().and_then(|c| spawn_events(c, &new));
+				}
+
+				if restart_workers || new.log_workers_gen != shared.log_workers_gen {
+					for task in std::mem::take(&mut log_tasks).into_values() {
+						task.abort();
+					}
+				}
+
+				if restart_workers {
+					self_metrics::worker_terminated("restart", running_workers(&tasks));
+					for (cont, worker) in std::mem::take(&mut tasks) {
+						worker.handle.abort();
+						health::container_stopped(&cont);
+					}
+				}
+
+				shared = new;
+
+				// fall through to re-evaluate which containers are watched right away
+			}
+			_ = cancel.cancelled() => { break }
+		}
+
+		// streams that ended while the engine was unreachable are restarted once it's back
+		let mut reconnected = client.ensure_tunnel().await;
+
+		// the engine behind an address can change while it's unreachable, e.g. upgraded, or docker replaced with podman
+		if !detected {
+			let detect = engine::detect(&mut client, &name);
+			let engine = tokio::select! {
+				res = detect => res,
+				_ = cancel.cancelled() => { break }
+			};
+
+			let engine = match engine {
+				Ok(e) => e,
+				Err(e) => {
+					// keep going, docker may come back, and /readyz reports this in the meantime
+					error(format_args!("Failed to connect to docker: {e}"), [("docker_host", &*name)]);
+					health::docker_reachable(&name, false);
+					health::tick(&name, container_search_interval.period());
+					continue;
+				}
+			};
+			detected = true;
+			reconnected = true;
+
+			let changed = conn
+				.as_ref()
+				.is_none_or(|c| c.engine != engine || c.docker.client_version() != client.docker.client_version());
+
+			if changed {
+				let new_conn = Arc::new(Connection {
+					docker: client.docker.clone(),
+					engine,
+					host: host.clone(),
+				});
+
+				if let Some(task) = events.take() {
+					task.abort();
+				}
+				for task in std::mem::take(&mut log_tasks).into_values() {
+					task.abort();
+				}
+				self_metrics::worker_terminated("restart", running_workers(&tasks));
+				for (cont, worker) in std::mem::take(&mut tasks) {
+					worker.handle.abort();
+					health::container_stopped(&cont);
+				}
+
+				events = spawn_events(&new_conn, &shared);
+				conn = Some(new_conn);
+			}
+		}
+
+		// an unreachable engine can take a while to time out, which shouldn't hold up stopping
+		let list = self_metrics::time_docker("list_containers", client.docker.list_containers::<String>(None));
+		let listed = tokio::select! {
+			res = list => res,
+			_ = cancel.cancelled() => { break }
+		};
+
+		let containers = match listed {
+			Ok(c) => c,
+			Err(e) => {
+				error(format_args!("Failed to list containers: {e}"), [("docker_host", &*name)]);
+				health::docker_reachable(&name, false);
+				health::tick(&name, container_search_interval.period());
+				detected = false;
+				continue;
+			}
+		};
+		health::docker_reachable(&name, true);
+		health::tick(&name, container_search_interval.period());
+
+		let Some(conn) = &conn else {
+			continue;
+		};
+
+		let mut containers: Vec<_> = containers.into_iter().filter(|c| c.id.is_some()).collect();
+
+		containers.sort_by(|a, b| a.id.as_ref().unwrap().cmp(b.id.as_ref().unwrap()));
+
+		inspections.retain(|cont, _| containers.binary_search_by(|c| c.id.as_ref().unwrap().cmp(cont)).is_ok());
+
+		let mut to_remove = Vec::new();
+		let mut killed = 0;
+
+		for (cont, worker) in &tasks {
+			// funny O(n^2) loop
+			if containers
+				.binary_search_by(|c| c.id.as_ref().unwrap().cmp(cont))
+				.is_err()
+			{
+				debug(format_args!("Killing worker for {}", cont), [("container_id", &**cont)]);
+				// workers whose stats stream already ended have already counted themselves as terminated
+				if !worker.handle.is_finished() {
+					killed += 1;
+				}
+				worker.handle.abort();
+				to_remove.push(cont.clone());
+			}
+		}
+
+		self_metrics::worker_terminated("removed", killed);
+
+		for cont in to_remove.into_iter() {
+			tasks.remove(&cont);
+			health::container_stopped(&cont);
+		}
+
+		// log workers end by themselves when their container stops, so are relaunched if it is running again
+		let cfg = config::current();
+		let logger = shared
+			.logger_provider
+			.as_ref()
+			.filter(|_| cfg.container_logs)
+			.map(|p| Arc::new(p.logger("cspy_container_logs")));
+
+		log_tasks.retain(|cont, handle| {
+			let keep = logger.is_some()
+				&& containers
+					.binary_search_by(|c| c.id.as_ref().unwrap().cmp(cont))
+					.is_ok_and(|i| logs_task::should_forward(&containers[i], &cfg));
+
+			if !keep {
+				handle.abort();
+			}
+			keep
+		});
+
+		if let Some(logger) = &logger {
+			for cont in &containers {
+				let id_string = cont.id.as_ref().unwrap();
+				if logs_task::should_forward(cont, &cfg) && log_tasks.get(id_string).is_none_or(|t| t.is_finished()) {
+					debug(format_args!("Launching log worker for {}", id_string), [("container_id", &**id_string)]);
+					let inspection = inspections.entry(id_string.clone()).or_default().clone();
+					log_tasks.insert(
+						id_string.clone(),
+						logs_task::launch_logs_task(cont.clone(), conn.clone(), logger.clone(), inspection),
+					);
+				}
+			}
+		}
+
+		drop(cfg);
+
+		// now, add any new ones. paused containers are listed as well, and keep their workers
+		for cont in containers {
+			let id_string = cont.id.as_ref().unwrap();
+			let paused = cont.state.as_deref() == Some("paused");
+
+			match tasks.get(id_string) {
+				Some(worker) if !worker.handle.is_finished() => {
+					worker.paused.send_replace(paused);
+				}
+				// a worker also ends when its container looks like it exited, while it may still be listed for a bit.
+				// those are left alone, unless it's running again, e.g. it was restarted between two lists, or after
+				// reconnecting, as the stream may have ended because the connection dropped
+				Some(_) if !reconnected && cont.state.as_deref() != Some("running") => {}
+				_ => {
+					debug(format_args!("Launching worker for {}", id_string), [("container_id", &**id_string)]);
+					let (paused, paused_rx) = watch::channel(paused);
+					// all this string cloning hurts me
+					let id_string = id_string.clone();
+					// a worker that ended may have been for an earlier run of the container, so inspect it again
+					let inspection = if tasks.contains_key(&id_string) {
+						let fresh = labels::SharedInspect::default();
+						inspections.insert(id_string.clone(), fresh.clone());
+						fresh
+					} else {
+						inspections.entry(id_string.clone()).or_default().clone()
+					};
+					let handle =
+						stats_task::launch_stats_task(cont, conn.clone(), shared.meter.clone(), paused_rx, inspection);
+					tasks.insert(id_string, Worker { handle, paused });
+					self_metrics::worker_launched();
+				}
+			}
+		}
+
+		self_metrics::watched_containers(host.as_deref(), tasks.len());
+	}
+
+	// abort all stats, log, and event tasks
+	self_metrics::worker_terminated("shutdown", running_workers(&tasks));
+	for (cont, worker) in tasks {
+		worker.handle.abort();
+		health::container_stopped(&cont);
+	}
+	for task in log_tasks.into_values().chain(events) {
+		task.abort();
+	}
+
+	self_metrics::watched_containers(host.as_deref(), 0);
+	health::host_removed(&name);
+}